@@ -0,0 +1,142 @@
+//! The WebSocket close handshake and signal handling
+use std::io;
+use std::io::Write;
+use std::process::exit;
+use std::sync::mpsc::{Sender as ChannelSender, Receiver as ChannelReceiver};
+use std::time::{Duration, Instant};
+
+use ctrlc;
+
+use websocket::Message;
+use wsta::deflate::Sender as SenderObj;
+
+use wsta::Error;
+use wsta::error::EXIT_INTERRUPTED;
+use wsta::options::Options;
+
+use ws;
+use ws::{Event, ReaderEvent};
+use output::Output;
+
+/// The close code of a normal closure
+pub const NORMAL_CLOSURE: u16 = 1000;
+
+/// Handle SIGINT and SIGTERM (Ctrl-C and Ctrl-Break on Windows). Instead of
+/// exiting right away, an `Event::Signal` with the exit code to use is sent
+/// to `events`, so the connection can be closed properly first. A second
+/// signal exits immediately, in case closing the connection hangs.
+pub fn spawn_signal_handler(events: ChannelSender<Event>) -> Result<(), Error> {
+    let mut received = false;
+
+    // The handler is called from a thread of its own
+    let result = ctrlc::set_handler(move || {
+        log!(2, "Received signal");
+
+        if received || events.send(Event::Signal(EXIT_INTERRUPTED)).is_err() {
+            exit(EXIT_INTERRUPTED);
+        }
+
+        received = true;
+    });
+
+    result.map_err(|err| {
+        let err = match err {
+            ctrlc::Error::System(err) => err,
+            err => io::Error::new(io::ErrorKind::Other, format!("{}", err))
+        };
+
+        Error::Io(String::from("Failed to set up signal handling"), err)
+    })
+}
+
+/// Send a close frame with the close code and reason from the options, and
+/// wait up to `--close-timeout` seconds for the server to answer with a close
+/// frame of its own. Frames still waiting to be sent are dropped.
+///
+/// # Returns
+/// Returns the close code of the reply, or an error message if the server
+/// did not reply.
+pub fn close(sender: &mut SenderObj,
+             events: &ChannelReceiver<Event>,
+             session: u64,
+             options: &Options,
+             output: &Output) -> Result<Option<u16>, String> {
+
+    log!(2, "Closing connection with code {}", options.close_code);
+    let message = Message::close_because(options.close_code,
+                                         options.close_reason.clone());
+
+    if let Err(err) = ws::send_message(sender, &message, output) {
+        return Err(format!("Failed to send close frame: {}", err));
+    }
+
+    let timeout = Duration::from_secs(options.close_timeout);
+    let deadline = Instant::now().checked_add(timeout);
+
+    loop {
+        let event = match ws::next_event(events, deadline) {
+            Some(event) => event,
+            None => {
+                return Err(format!("No close frame received within {} \
+                                    seconds", timeout.as_secs()));
+            }
+        };
+
+        match event {
+            Event::Reader(id, ReaderEvent::Close(code, reason)) if id == session => {
+                print_close(code, &reason);
+                return Ok(code);
+            },
+            Event::Reader(id, ReaderEvent::Disconnected) if id == session => {
+                return Err(String::from("Connection lost before the server \
+                                         answered the close frame"));
+            },
+
+            // Pings are no longer answered once the close frame is sent
+            event => log!(3, "Ignoring event while closing: {:?}", event)
+        }
+    }
+}
+
+/// Answer a close frame sent by the server, echoing its close code
+pub fn answer_close(sender: &mut SenderObj,
+                    code: Option<u16>,
+                    output: &Output) {
+
+    let message = match code {
+        Some(code) => Message::close_because(code, ""),
+        None => Message::close()
+    };
+
+    // The server may already have dropped the connection, which is fine
+    ws::send_message(sender, &message, output).unwrap_or(());
+}
+
+/// Print the close code and reason sent by the server to stderr
+pub fn print_close(code: Option<u16>, reason: &str) {
+    match (code, reason.is_empty()) {
+        (Some(code), true) => stderr!("Connection closed with code {}", code),
+        (Some(code), false) => stderr!("Connection closed with code {}: {}",
+                                       code, reason),
+        (None, _) => stderr!("Connection closed without a close code")
+    }
+}
+
+/// Check that `code` may be sent in a close frame
+pub fn validate_close_code(code: u16) -> Result<(), Error> {
+    if !is_valid_close_code(code) {
+        return Err(Error::InvalidArgument(format!("Invalid close code {}. Use \
+                                                   1000-1003, 1007-1011 or \
+                                                   3000-4999", code)));
+    }
+
+    Ok(())
+}
+
+/// Whether `code` may be sent in a close frame
+pub fn is_valid_close_code(code: u16) -> bool {
+    match code {
+        1000..=1003 | 1007..=1011 | 3000..=4999 => true,
+        _ => false
+    }
+}
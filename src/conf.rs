@@ -1,15 +1,23 @@
 use std::io;
 use std::io::Write;
 use std::path::PathBuf;
-use std::process::exit;
 use std::option::Option;
+use std::str::FromStr;
+use std::fmt::Display;
 
 use config::reader::from_file;
 use config::types::{ScalarValue,Value,Config};
 use config::error::ConfigErrorKind::{IoError,ParseError};
 
+use regex::Regex;
+
+use error::Error;
+use http::{LoginStep, Extract, ExtractFrom};
+use json_path::JsonPath;
+
 #[cfg(unix)] use xdg::BaseDirectories;
 
+#[cfg(test)] use error::EXIT_INVALID_ARGUMENT;
 #[cfg(test)] use std::fs;
 #[cfg(test)] use std::fs::{File,DirBuilder};
 #[cfg(test)] use std::env;
@@ -20,29 +28,28 @@ use config::error::ConfigErrorKind::{IoError,ParseError};
 /// Reads the configuration file and copies the values into a temporary
 /// options object. The object is the overridden with the parameters
 /// specified on the command line.
-pub fn read_conf_file(profile: Option<String>) -> Option<Config> {
+pub fn read_conf_file(profile: Option<String>) -> Result<Option<Config>, Error> {
 
     // Lookup config_folder based on OS
     let conf_path = get_config_path(profile);
 
     if conf_path.is_none() {
-        return None;
+        return Ok(None);
     }
 
     // Read the configuration path and handle errors
     match from_file(&conf_path.unwrap()) {
-        Ok(config) => Some(config),
+        Ok(config) => Ok(Some(config)),
         Err(config_err) => {
             match config_err.kind {
 
                 // User has not made a config file, which is fine
-                IoError => None,
+                IoError => Ok(None),
 
                 // User has a config file, but it has syntax errors
                 ParseError => {
-                    stderr!("ERROR: Failed to parse wsta config file: {}",
-                            config_err);
-                    exit(1);
+                    Err(Error::Config(format!("Failed to parse wsta config \
+                                               file: {}", config_err)))
                 }
             }
         }
@@ -64,6 +71,49 @@ pub fn get_bool(config: &Config, key: &str) -> bool {
    config.lookup_boolean_or(key, false)
 }
 
+/// Utility method for fetching config as an unsigned integer. Both 32-bit
+/// (`5`) and 64-bit (`5L`) integers are accepted. Fails if the value is
+/// negative.
+pub fn get_u64_or(config: &Config, key: &str, default: u64) -> Result<u64, Error> {
+    let value = match config.lookup_integer32(key) {
+        Some(value) => value as i64,
+        None => match config.lookup_integer64(key) {
+            Some(value) => value,
+            None => return Ok(default)
+        }
+    };
+
+    if value < 0 {
+        return Err(Error::Config(format!("Invalid value for {}: {} is \
+                                          negative", key, value)));
+    }
+
+    Ok(value as u64)
+}
+
+/// Utility method for fetching config as a floating point number. Integers
+/// are accepted as well.
+pub fn get_f64_or(config: &Config, key: &str, default: f64) -> f64 {
+    config.lookup_floating64(key)
+        .or(config.lookup_floating32(key).map(|value| value as f64))
+        .or(config.lookup_integer32(key).map(|value| value as f64))
+        .or(config.lookup_integer64(key).map(|value| value as f64))
+        .unwrap_or(default)
+}
+
+/// Utility method for fetching config as a String parsed into `T`. Fails
+/// if the value cannot be parsed.
+pub fn get_parsed_or<T>(config: &Config, key: &str, default: T) -> Result<T, Error>
+    where T: FromStr, T::Err: Display {
+
+    match config.lookup_str(key) {
+        Some(value) => value.parse().map_err(|err| {
+            Error::Config(format!("Invalid value for {}: {}", key, err))
+        }),
+        None => Ok(default)
+    }
+}
+
 /// Utility method for fetching config as Vec
 pub fn get_vec(config: &Config, key: &str) -> Vec<String> {
 
@@ -103,6 +153,73 @@ pub fn get_vec(config: &Config, key: &str) -> Vec<String> {
     result
 }
 
+/// Utility method for fetching a list of login steps, such as
+///
+/// ```text
+/// login_steps = (
+///   { url = "https://example.com/login";
+///     extract = ( { name = "csrf"; regex = "csrf\" value=\"([^\"]+)"; } ); },
+///   { method = "POST"; url = "https://example.com/login";
+///     data = "user=me&csrf={{csrf}}";
+///     extract = ( { name = "token"; json = ".access_token"; } ); }
+/// );
+/// ```
+///
+/// Fails if a step is invalid.
+pub fn get_login_steps(config: &Config, key: &str) -> Result<Vec<LoginStep>, Error> {
+    let mut steps = Vec::new();
+
+    while config.lookup(&format!("{}.[{}]", key, steps.len())).is_some() {
+        let step = format!("{}.[{}]", key, steps.len());
+        let field = |name: &str| format!("{}.{}", step, name);
+
+        let url = get_str(config, &field("url"));
+        if url.is_empty() {
+            return Err(Error::Config(format!("{} has no url", step)));
+        }
+
+        let mut extract = Vec::new();
+        while config.lookup(&format!("{}.[{}]", field("extract"), extract.len())).is_some() {
+            let path = format!("{}.[{}]", field("extract"), extract.len());
+            extract.push(get_extract(config, &path)?);
+        }
+
+        steps.push(LoginStep {
+            method: get_str(config, &field("method")),
+            url: url,
+            data: get_str(config, &field("data")),
+            headers: get_vec(config, &field("headers")),
+            extract: extract
+        });
+    }
+
+    Ok(steps)
+}
+
+/// Read a value to extract from a login response, which has a name and
+/// exactly one of header, json or regex
+fn get_extract(config: &Config, path: &str) -> Result<Extract, Error> {
+    let field = |name: &str| get_str(config, &format!("{}.{}", path, name));
+    let (name, header, json, regex) = (field("name"), field("header"),
+                                       field("json"), field("regex"));
+
+    let from = match (header.is_empty(), json.is_empty(), regex.is_empty()) {
+        (false, true, true) => Ok(ExtractFrom::Header(header)),
+        (true, false, true) => JsonPath::parse(&json).map(ExtractFrom::Json),
+        (true, true, false) => Regex::new(&regex).map(ExtractFrom::Regex)
+            .map_err(|err| format!("invalid regex: {}", err)),
+        _ => Err(String::from("use exactly one of header, json or regex"))
+    };
+
+    match (name.is_empty(), from) {
+        (false, Ok(from)) => Ok(Extract { name: name, from: from }),
+        (true, _) => Err(Error::Config(format!("{} has no name", path))),
+        (false, Err(err)) => {
+            Err(Error::Config(format!("Invalid value for {}: {}", path, err)))
+        }
+    }
+}
+
 /// Determine the conf file location using the special %APPDATA% directory of
 /// windows.
 ///
@@ -146,12 +263,118 @@ fn get_config_path(profile: Option<String>) -> Option<PathBuf> {
     xdg_dirs.find_config_file("wsta.conf")
 }
 
+/// Determine where to keep the data file `name` of a profile, next to the
+/// conf file in %APPDATA%/wsta/someapp
+#[cfg(windows)]
+pub fn get_data_path(profile: Option<String>, name: &str) -> Option<PathBuf> {
+
+    let mut path = match std::env::var_os("APPDATA") {
+        Some(app_data) => PathBuf::from(app_data),
+        None => {
+            log!(1, "APPDATA is not set, not keeping {}", name);
+            return None;
+        }
+    };
+    path.push("wsta");
+
+    if profile.is_some() {
+        path.push(profile.unwrap());
+    }
+
+    path.push(name);
+
+    Some(path)
+}
+
+/// Determine where to keep the data file `name` of a profile using the XDG
+/// basedir spec, which defaults to $HOME/.local/share/wsta/someapp/name.
+/// The directory is created if needed.
+#[cfg(unix)]
+pub fn get_data_path(profile: Option<String>, name: &str) -> Option<PathBuf> {
+
+    let xdg_dirs_option = match profile {
+        Some(p) => BaseDirectories::with_profile(env!("CARGO_PKG_NAME"), p),
+        None => BaseDirectories::with_prefix(env!("CARGO_PKG_NAME"))
+    };
+
+    match xdg_dirs_option.map(|xdg_dirs| xdg_dirs.place_data_file(name)) {
+        Ok(Ok(path)) => Some(path),
+        _ => {
+            log!(1, "Could not create the data directory for {}", name);
+            None
+        }
+    }
+}
+
 
 
 ///////////////////////// TESTS ///////////////////////////
 // Tests need to support both windows and unix, and will //
 // run CI in both these systems                          //
 
+#[test]
+fn get_u64_or_works() {
+    let conf = "a = 5;\nb = 6L;\nc = \"foo\";".parse::<Config>()
+        .expect("Could not parse test config");
+
+    assert_eq!(get_u64_or(&conf, "a", 1).unwrap(), 5);
+    assert_eq!(get_u64_or(&conf, "b", 1).unwrap(), 6);
+    assert_eq!(get_u64_or(&conf, "c", 1).unwrap(), 1);
+    assert_eq!(get_u64_or(&conf, "ricepudding", 1).unwrap(), 1);
+
+    let conf = "a = -1;\nb = -1L;".parse::<Config>()
+        .expect("Could not parse test config");
+    assert!(get_u64_or(&conf, "a", 1).is_err());
+    assert!(get_u64_or(&conf, "b", 1).is_err());
+}
+
+#[test]
+fn get_f64_or_works() {
+    let conf = "a = 0.5;\nb = 2;\nc = \"foo\";".parse::<Config>()
+        .expect("Could not parse test config");
+
+    assert_eq!(get_f64_or(&conf, "a", 1.5), 0.5);
+    assert_eq!(get_f64_or(&conf, "b", 1.5), 2.0);
+    assert_eq!(get_f64_or(&conf, "c", 1.5), 1.5);
+    assert_eq!(get_f64_or(&conf, "ricepudding", 1.5), 1.5);
+}
+
+#[test]
+fn get_login_steps_works() {
+    let conf = r#"
+        login_steps = (
+          { url = "https://example.com/login";
+            extract = ( { name = "csrf"; header = "X-CSRF-Token"; } ); },
+          { method = "POST"; url = "https://example.com/session";
+            data = "csrf={{csrf}}"; headers = ["Accept: application/json"];
+            extract = ( { name = "token"; json = ".access_token"; },
+                        { name = "id"; regex = "id=(\\d+)"; } ); }
+        );"#.parse::<Config>().expect("Could not parse test config");
+
+    let steps = get_login_steps(&conf, "login_steps").unwrap();
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[0].method, "");
+    assert_eq!(steps[0].extract[0].name, "csrf");
+    assert_eq!(steps[1].url, "https://example.com/session");
+    assert_eq!(steps[1].headers, vec!["Accept: application/json"]);
+    assert_eq!(steps[1].extract.iter().map(|extract| &extract.name[..])
+               .collect::<Vec<&str>>(), vec!["token", "id"]);
+
+    match steps[1].extract[1].from {
+        ExtractFrom::Regex(ref regex) => assert!(regex.is_match("id=42")),
+        ref from => panic!("Unexpected extract {:?}", from)
+    }
+
+    assert!(get_login_steps(&conf, "missing").unwrap().is_empty());
+
+    // An extract without header, json or regex is an error
+    let conf = r#"login_steps = ( { url = "https://example.com";
+                                    extract = ( { name = "csrf"; } ); } );"#
+        .parse::<Config>().expect("Could not parse test config");
+    assert_eq!(get_login_steps(&conf, "login_steps").unwrap_err().exit_code(),
+               EXIT_INVALID_ARGUMENT);
+}
+
 #[test]
 fn config_path_is_read() {
     backup_user_config();
@@ -177,7 +400,7 @@ fn configfile_is_read() {
     backup_user_config();
     create_dummy_conf(None);
 
-    let conf = read_conf_file(None).expect("Could not read config file");
+    let conf = read_conf_file(None).unwrap().expect("Could not read config file");
 
     // Assert is parsed properly
     let result: &str = conf.lookup_str("login_cookie_name")
@@ -195,7 +418,7 @@ fn get_vec_works() {
     backup_user_config();
     create_dummy_conf(None);
 
-    let conf = read_conf_file(None).expect("Could not read config file");
+    let conf = read_conf_file(None).unwrap().expect("Could not read config file");
 
     // Assert is parsed properly
     let key_name = "headers";
@@ -226,7 +449,7 @@ fn config_profile_works() {
 
     create_dummy_conf(Some(conf_file));
 
-    let conf = read_conf_file(Some(profile_name))
+    let conf = read_conf_file(Some(profile_name)).unwrap()
         .expect("Could not read config file");
 
     // Assert is parsed properly
@@ -257,7 +480,7 @@ fn config_profile_works() {
 
     create_dummy_conf(Some(conf_file));
 
-    let conf = read_conf_file(Some(profile_name))
+    let conf = read_conf_file(Some(profile_name)).unwrap()
         .expect("Could not read config file");
 
     // Assert is parsed properly
@@ -0,0 +1,424 @@
+//! Cookies set by the login request and the server, kept in a jar that can
+//! be saved to and loaded from a file with `--cookie-jar`.
+//!
+//! The file is in the Netscape format used by curl and wget, so that jars
+//! can be shared with them. Cookies are only sent to the hosts and paths
+//! they were set for, and secure cookies only over https and wss.
+use std::io;
+use std::io::{Read, Write, ErrorKind};
+use std::fs::File;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cookie::Cookie as CookiePair;
+
+use hyper::Url;
+use hyper::header::Cookie;
+
+/// The first line of a cookie jar file, which curl insists on
+const HEADER: &'static str = "# Netscape HTTP Cookie File";
+
+// curl marks HttpOnly cookies by prefixing the domain with this
+const HTTP_ONLY_PREFIX: &'static str = "#HttpOnly_";
+
+// The names of the cookies set by the login response are kept in a comment,
+// which curl and wget ignore
+const LOGIN_COOKIES_PREFIX: &'static str = "# wsta login cookies: ";
+
+/// A cookie along with where it may be sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredCookie {
+    pub name: String,
+    pub value: String,
+
+    /// The host the cookie was set for, without a leading dot
+    pub domain: String,
+
+    /// Whether the cookie is sent to subdomains of `domain` as well, which
+    /// is the case when it was set with a Domain attribute
+    pub include_subdomains: bool,
+
+    pub path: String,
+
+    /// Whether the cookie is only sent over https and wss
+    pub secure: bool,
+
+    pub http_only: bool,
+
+    /// The Unix time the cookie expires at. Session cookies do not expire,
+    /// and are kept in the jar until they are replaced.
+    pub expires: Option<u64>
+}
+
+/// A collection of cookies
+#[derive(Debug, Clone, Default)]
+pub struct CookieJar {
+    cookies: Vec<StoredCookie>,
+
+    /// The names of the cookies the last `--login` response set
+    login_cookies: Vec<String>
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        CookieJar::default()
+    }
+
+    /// Load the jar saved at `path`. A jar that has not been saved yet is
+    /// empty.
+    pub fn load(path: &str) -> io::Result<CookieJar> {
+        let mut text = String::new();
+
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+            Ok(_) => Ok(CookieJar::parse(&text)),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                log!(2, "Cookie jar {} does not exist yet", path);
+                Ok(CookieJar::new())
+            },
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Save the jar to `path`, leaving out expired cookies
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(self.to_netscape().as_bytes())
+    }
+
+    /// Parse a jar in the Netscape format. Lines that cannot be parsed are
+    /// skipped, as are expired cookies.
+    pub fn parse(text: &str) -> CookieJar {
+        let now = unix_time();
+        let mut jar = CookieJar::new();
+
+        for line in text.lines() {
+            if line.starts_with(LOGIN_COOKIES_PREFIX) {
+                jar.login_cookies = line[LOGIN_COOKIES_PREFIX.len()..]
+                    .split(',')
+                    .map(|name| String::from(name.trim()))
+                    .filter(|name| !name.is_empty())
+                    .collect();
+                continue;
+            }
+
+            let (line, http_only) = if line.starts_with(HTTP_ONLY_PREFIX) {
+                (&line[HTTP_ONLY_PREFIX.len()..], true)
+            } else {
+                (line, false)
+            };
+
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            let expires = fields.get(4).and_then(|expires| expires.parse::<u64>().ok());
+
+            let cookie = match (fields.len(), expires) {
+                (7, Some(expires)) => StoredCookie {
+                    name: String::from(fields[5]),
+                    value: String::from(fields[6]),
+                    domain: fields[0].trim_start_matches('.').to_lowercase(),
+                    include_subdomains: fields[1] == "TRUE",
+                    path: String::from(fields[2]),
+                    secure: fields[3] == "TRUE",
+                    http_only: http_only,
+                    expires: if expires == 0 { None } else { Some(expires) }
+                },
+                _ => {
+                    log!(1, "Skipping invalid line in cookie jar: {}", line);
+                    continue;
+                }
+            };
+
+            if !cookie.is_expired(now) {
+                jar.cookies.push(cookie);
+            }
+        }
+
+        jar
+    }
+
+    /// The jar in the Netscape format
+    pub fn to_netscape(&self) -> String {
+        let now = unix_time();
+        let mut text = format!("{}\n# Written by wsta, edit at your own risk.\n",
+                               HEADER);
+
+        if !self.login_cookies.is_empty() {
+            text.push_str(&format!("{}{}\n", LOGIN_COOKIES_PREFIX,
+                                   self.login_cookies.join(",")));
+        }
+        text.push('\n');
+
+        for cookie in self.cookies.iter().filter(|cookie| !cookie.is_expired(now)) {
+            let bool_field = |value| if value { "TRUE" } else { "FALSE" };
+
+            text.push_str(&format!("{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                                   if cookie.http_only { HTTP_ONLY_PREFIX } else { "" },
+                                   if cookie.include_subdomains { "." } else { "" },
+                                   cookie.domain,
+                                   bool_field(cookie.include_subdomains),
+                                   cookie.path,
+                                   bool_field(cookie.secure),
+                                   cookie.expires.unwrap_or(0),
+                                   cookie.name,
+                                   cookie.value));
+        }
+
+        text
+    }
+
+    /// Store the cookies of a Set-Cookie header received from `url`.
+    /// Cookies for other domains than that of `url` are rejected, and
+    /// cookies that have already expired remove the cookie they replace.
+    pub fn store(&mut self, url: &Url, set_cookies: &[CookiePair]) {
+        let now = unix_time();
+        let host = url.host_str().unwrap_or("").to_lowercase();
+
+        for set_cookie in set_cookies {
+            let cookie = match from_set_cookie(set_cookie, &host, url.path(), now) {
+                Some(cookie) => cookie,
+                None => {
+                    log!(1, "Rejected cookie {} set for another domain than {}",
+                         set_cookie.name, host);
+                    continue;
+                }
+            };
+
+            log!(3, "Storing cookie: {:?}", cookie);
+            self.cookies.retain(|stored| {
+                stored.name != cookie.name ||
+                stored.domain != cookie.domain ||
+                stored.path != cookie.path
+            });
+
+            if !cookie.is_expired(now) {
+                self.cookies.push(cookie);
+            }
+        }
+    }
+
+    /// The cookies to send to `url`, with the most specific paths first
+    pub fn matching(&self, url: &Url) -> Vec<&StoredCookie> {
+        let now = unix_time();
+        let host = url.host_str().unwrap_or("").to_lowercase();
+        let secure = match url.scheme() {
+            "https" | "wss" => true,
+            _ => false
+        };
+
+        let mut cookies = self.cookies.iter()
+            .filter(|cookie| !cookie.is_expired(now))
+            .filter(|cookie| !cookie.secure || secure)
+            .filter(|cookie| cookie.matches_domain(&host))
+            .filter(|cookie| cookie.matches_path(url.path()))
+            .collect::<Vec<&StoredCookie>>();
+
+        cookies.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+        cookies
+    }
+
+    /// Remember which cookies the login response set, so that later runs
+    /// can tell whether the jar still holds a session
+    pub fn set_login_cookies(&mut self, set_cookies: &[CookiePair]) {
+        self.login_cookies = set_cookies.iter()
+            .map(|set_cookie| set_cookie.name.clone())
+            .collect();
+    }
+
+    /// Whether one of the cookies the login response set would be sent to
+    /// `url`, in which case logging in again can be skipped. Other cookies,
+    /// such as those set by the server, do not count.
+    pub fn has_login_cookie(&self, url: &Url) -> bool {
+        self.matching(url).iter()
+            .any(|cookie| self.login_cookies.contains(&cookie.name))
+    }
+
+    /// A Cookie header with the cookies to send to `url`, if there are any
+    pub fn header(&self, url: &Url) -> Option<Cookie> {
+        let pairs = self.matching(url).into_iter()
+            .map(|cookie| CookiePair::new(cookie.name.clone(), cookie.value.clone()))
+            .collect::<Vec<CookiePair>>();
+
+        if pairs.is_empty() {
+            None
+        } else {
+            Some(Cookie(pairs))
+        }
+    }
+}
+
+impl StoredCookie {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+
+    fn matches_domain(&self, host: &str) -> bool {
+        host == self.domain ||
+        (self.include_subdomains && host.ends_with(&format!(".{}", self.domain)))
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        path == self.path ||
+        (path.starts_with(&self.path) &&
+         (self.path.ends_with('/') || path[self.path.len()..].starts_with('/')))
+    }
+}
+
+/// Turn a cookie set by `host` for a request to `path` into one that can be
+/// stored, or None if it was set for a domain `host` is not part of
+fn from_set_cookie(set_cookie: &CookiePair,
+                   host: &str,
+                   path: &str,
+                   now: u64) -> Option<StoredCookie> {
+
+    let (domain, include_subdomains) = match set_cookie.domain {
+        Some(ref domain) => {
+            let domain = domain.trim_start_matches('.').to_lowercase();
+
+            if host != domain && !host.ends_with(&format!(".{}", domain)) {
+                return None;
+            }
+
+            (domain, true)
+        },
+        None => (String::from(host), false)
+    };
+
+    // Max-Age takes precedence over Expires
+    let expires = match (set_cookie.max_age, set_cookie.expires) {
+        (Some(max_age), _) => Some(now + max_age),
+        (None, Some(expires)) => Some(expires.to_timespec().sec.max(0) as u64),
+        (None, None) => None
+    };
+
+    let path = match set_cookie.path {
+        Some(ref path) if path.starts_with('/') => path.clone(),
+        _ => default_path(path)
+    };
+
+    Some(StoredCookie {
+        name: set_cookie.name.clone(),
+        value: set_cookie.value.clone(),
+        domain: domain,
+        include_subdomains: include_subdomains,
+        path: path,
+        secure: set_cookie.secure,
+        http_only: set_cookie.httponly,
+        expires: expires
+    })
+}
+
+/// The path of a cookie set without a Path attribute, which is the
+/// directory of the request path (RFC 6265, section 5.1.4)
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => String::from("/"),
+        Some(end) => String::from(&path[..end])
+    }
+}
+
+/// The current Unix time, in seconds
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn cookies_are_sent_where_they_were_set() {
+    let login = Url::parse("https://auth.example.com/sso/login").unwrap();
+    let mut jar = CookieJar::new();
+
+    let mut shared = CookiePair::new(String::from("shared"), String::from("1"));
+    shared.domain = Some(String::from(".example.com"));
+    shared.path = Some(String::from("/"));
+
+    let mut secure = CookiePair::new(String::from("secure"), String::from("2"));
+    secure.secure = true;
+
+    let mut foreign = CookiePair::new(String::from("foreign"), String::from("3"));
+    foreign.domain = Some(String::from("example.org"));
+
+    jar.store(&login, &[shared, secure, foreign]);
+
+    let names = |jar: &CookieJar, url: &str| {
+        jar.matching(&Url::parse(url).unwrap()).iter()
+            .map(|cookie| cookie.name.clone())
+            .collect::<Vec<String>>()
+    };
+
+    assert_eq!(names(&jar, "wss://auth.example.com/sso/ws"), vec!["secure", "shared"]);
+    assert_eq!(names(&jar, "ws://auth.example.com/sso/ws"), vec!["shared"]);
+    assert_eq!(names(&jar, "wss://auth.example.com/ssoother"), vec!["shared"]);
+    assert_eq!(names(&jar, "wss://ws.example.com/"), vec!["shared"]);
+    assert!(names(&jar, "wss://example.org/").is_empty());
+
+    // An expired cookie removes the one it replaces
+    let mut removed = CookiePair::new(String::from("shared"), String::new());
+    removed.domain = Some(String::from("example.com"));
+    removed.path = Some(String::from("/"));
+    removed.max_age = Some(0);
+    jar.store(&login, &[removed]);
+    assert!(names(&jar, "wss://ws.example.com/").is_empty());
+}
+
+#[test]
+fn jars_survive_the_netscape_format() {
+    let text = "# Netscape HTTP Cookie File\n\
+                .example.com\tTRUE\t/\tFALSE\t0\tsession\tabc\n\
+                #HttpOnly_example.com\tFALSE\t/api\tTRUE\t4102444800\tid\t42\n\
+                example.com\tFALSE\t/\tFALSE\t1\texpired\tx\n\
+                not a cookie\n";
+    let jar = CookieJar::parse(text);
+
+    assert_eq!(jar.cookies, vec![
+        StoredCookie {
+            name: String::from("session"),
+            value: String::from("abc"),
+            domain: String::from("example.com"),
+            include_subdomains: true,
+            path: String::from("/"),
+            secure: false,
+            http_only: false,
+            expires: None
+        },
+        StoredCookie {
+            name: String::from("id"),
+            value: String::from("42"),
+            domain: String::from("example.com"),
+            include_subdomains: false,
+            path: String::from("/api"),
+            secure: true,
+            http_only: true,
+            expires: Some(4102444800)
+        }
+    ]);
+
+    assert_eq!(CookieJar::parse(&jar.to_netscape()).cookies, jar.cookies);
+}
+
+#[test]
+fn only_login_cookies_skip_the_login() {
+    let login = Url::parse("https://example.com/login").unwrap();
+    let server = Url::parse("wss://example.com/ws").unwrap();
+    let mut jar = CookieJar::new();
+
+    // A cookie the server set during the handshake is not a session
+    let tracking = CookiePair::new(String::from("tracking"), String::from("1"));
+    jar.store(&server, &[tracking]);
+    assert!(!jar.has_login_cookie(&server));
+
+    let session = vec![CookiePair::new(String::from("session"), String::from("abc"))];
+    jar.store(&login, &session);
+    jar.set_login_cookies(&session);
+    assert!(jar.has_login_cookie(&server));
+
+    // The names of the login cookies are saved along with the jar
+    let loaded = CookieJar::parse(&jar.to_netscape());
+    assert_eq!(loaded.login_cookies, vec!["session"]);
+    assert!(loaded.has_login_cookie(&server));
+    assert!(!loaded.has_login_cookie(&Url::parse("wss://example.org/").unwrap()));
+}
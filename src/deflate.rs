@@ -0,0 +1,378 @@
+//! The permessage-deflate extension (RFC 7692).
+//!
+//! The extension is offered in the upgrade request unless `--no-compression`
+//! is given. When the server accepts it, the `Sender` and `Receiver` of the
+//! session compress and decompress the payload of data frames, so that the
+//! rest of wsta only ever sees plain messages.
+use std::io;
+use std::io::Write;
+
+use flate2::{Compress, Decompress, Compression, Flush};
+
+use websocket::Sender as WsSender;
+use websocket::Receiver as WsReceiver;
+use websocket::client::Sender as SenderObj;
+use websocket::client::Receiver as ReceiverObj;
+use websocket::dataframe::{DataFrame, Opcode};
+use websocket::header::WebSocketExtensions;
+use websocket::header::extensions::{Extension, Parameter};
+use websocket::result::{WebSocketError, WebSocketResult};
+use websocket::stream::WebSocketStream;
+use websocket::ws::dataframe::DataFrame as DataFrameTrait;
+
+/// The name of the extension, as used in Sec-WebSocket-Extensions
+pub const PERMESSAGE_DEFLATE: &'static str = "permessage-deflate";
+
+// Every compressed message ends with an empty stored block, which is left
+// out on the wire
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The parameters the server accepted permessage-deflate with
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Deflate {
+
+    /// The server starts every message with an empty window
+    pub server_no_context_takeover: bool,
+
+    /// We must start every message with an empty window
+    pub client_no_context_takeover: bool,
+
+    /// The window size the server compresses with, in bits
+    pub server_max_window_bits: Option<u8>
+}
+
+impl Deflate {
+
+    /// The extension to offer in the upgrade request. The size of our own
+    /// window cannot be changed, so client_max_window_bits is not offered.
+    pub fn offer() -> Extension {
+        Extension {
+            name: String::from(PERMESSAGE_DEFLATE),
+            params: Vec::new()
+        }
+    }
+
+    /// Read the parameters of a permessage-deflate extension accepted by the
+    /// server. Parameters that were not offered, are invalid or are given
+    /// more than once fail the handshake, as RFC 7692 requires.
+    pub fn accept(extension: &Extension) -> WebSocketResult<Deflate> {
+        let mut deflate = Deflate::default();
+        let mut seen = Vec::new();
+
+        for param in &extension.params {
+            let (name, value) = split_parameter(param);
+
+            if seen.contains(&name) {
+                return Err(invalid_parameter(param));
+            }
+            seen.push(name);
+
+            match (name, value) {
+                ("server_no_context_takeover", None) => {
+                    deflate.server_no_context_takeover = true;
+                },
+                ("client_no_context_takeover", None) => {
+                    deflate.client_no_context_takeover = true;
+                },
+                ("server_max_window_bits", Some(bits)) => {
+                    deflate.server_max_window_bits = Some(window_bits(bits)?);
+                },
+                _ => return Err(invalid_parameter(param))
+            }
+        }
+
+        Ok(deflate)
+    }
+}
+
+/// Find out whether the server accepted permessage-deflate, given the
+/// extensions `offered` in the upgrade request and those `accepted` in the
+/// response. The server may only accept it if it was offered, which may also
+/// have been done with a raw header.
+pub fn negotiate(offered: Option<&WebSocketExtensions>,
+                 accepted: Option<&WebSocketExtensions>)
+                 -> WebSocketResult<Option<Deflate>> {
+
+    let find = |extensions: Option<&WebSocketExtensions>| {
+        extensions.and_then(|extensions| {
+            extensions.0.iter()
+                .find(|extension| extension.name == PERMESSAGE_DEFLATE)
+                .cloned()
+        })
+    };
+
+    match (find(offered), find(accepted)) {
+        (Some(_), Some(extension)) => Deflate::accept(&extension).map(Some),
+        (None, Some(_)) => Err(WebSocketError::ResponseError(
+            "The server accepted permessage-deflate, which was not offered")),
+        (_, None) => Ok(None)
+    }
+}
+
+/// The name and value of `param`. The parser of rust-websocket leaves the
+/// value in the name, so it is split off here.
+fn split_parameter(param: &Parameter) -> (&str, Option<&str>) {
+    match param.value {
+        Some(ref value) => (param.name.trim(), Some(value.trim())),
+        None => {
+            let mut pair = param.name.splitn(2, '=').map(str::trim);
+            (pair.next().unwrap_or(""), pair.next())
+        }
+    }
+}
+
+/// Parse the value of a *_max_window_bits parameter
+fn window_bits(value: &str) -> WebSocketResult<u8> {
+    match value.trim_matches('"').parse::<u8>() {
+        Ok(bits) if bits >= 8 && bits <= 15 => Ok(bits),
+        _ => Err(WebSocketError::ResponseError("Invalid permessage-deflate \
+                                                window size"))
+    }
+}
+
+fn invalid_parameter(param: &Parameter) -> WebSocketError {
+    log!(1, "Unexpected permessage-deflate parameter: {}", param);
+    WebSocketError::ResponseError("Unexpected permessage-deflate parameter")
+}
+
+/// Compresses the payload of outgoing messages
+pub struct Compressor {
+    compress: Compress,
+    no_context_takeover: bool
+}
+
+impl Compressor {
+    pub fn new(deflate: &Deflate) -> Compressor {
+        Compressor {
+            compress: Compress::new(Compression::Default, false),
+            no_context_takeover: deflate.client_no_context_takeover
+        }
+    }
+
+    /// Compress a whole message, leaving out the trailing empty block
+    pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len() / 2 + 64);
+        let mut consumed = 0;
+
+        // The flush is only complete when there is output space to spare
+        loop {
+            let before = self.compress.total_in();
+            self.compress.compress_vec(&data[consumed..], &mut output, Flush::Sync);
+            consumed += (self.compress.total_in() - before) as usize;
+
+            if consumed == data.len() && output.len() < output.capacity() {
+                break;
+            }
+
+            let grow = output.capacity().max(64);
+            output.reserve(grow);
+        }
+
+        if output.ends_with(&TRAILER) {
+            let len = output.len() - TRAILER.len();
+            output.truncate(len);
+        }
+
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+
+        output
+    }
+}
+
+/// Decompresses the payload of incoming messages
+pub struct Decompressor {
+    decompress: Decompress,
+    no_context_takeover: bool
+}
+
+impl Decompressor {
+    pub fn new(deflate: &Deflate) -> Decompressor {
+        Decompressor {
+            decompress: Decompress::new(false),
+            no_context_takeover: deflate.server_no_context_takeover
+        }
+    }
+
+    /// Decompress a whole message, which lacks the trailing empty block
+    pub fn decompress(&mut self, data: &[u8]) -> WebSocketResult<Vec<u8>> {
+        let mut input = data.to_vec();
+        input.extend_from_slice(&TRAILER);
+
+        let mut output = Vec::with_capacity(data.len() * 4 + 64);
+        let mut consumed = 0;
+
+        loop {
+            let (before_in, before_out) = (self.decompress.total_in(),
+                                           self.decompress.total_out());
+
+            if self.decompress.decompress_vec(&input[consumed..], &mut output,
+                                              Flush::Sync).is_err() {
+                return Err(WebSocketError::DataFrameError("Invalid compressed \
+                                                           message"));
+            }
+
+            let read = (self.decompress.total_in() - before_in) as usize;
+            consumed += read;
+
+            if consumed == input.len() && output.len() < output.capacity() {
+                break;
+            }
+
+            // Neither input nor output moved, so the data must be truncated
+            if read == 0 && self.decompress.total_out() == before_out &&
+               output.len() < output.capacity() {
+                return Err(WebSocketError::DataFrameError("Truncated compressed \
+                                                           message"));
+            }
+
+            let grow = output.capacity().max(64);
+            output.reserve(grow);
+        }
+
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+
+        Ok(output)
+    }
+}
+
+/// Sends frames to the server, compressing data frames when
+/// permessage-deflate was negotiated
+pub struct Sender {
+    inner: SenderObj<WebSocketStream>,
+    compressor: Option<Compressor>
+}
+
+impl Sender {
+    pub fn new(inner: SenderObj<WebSocketStream>,
+               compressor: Option<Compressor>) -> Sender {
+        Sender {
+            inner: inner,
+            compressor: compressor
+        }
+    }
+
+    /// Shut down both halves of the underlying connection
+    pub fn shutdown_all(&mut self) -> io::Result<()> {
+        self.inner.shutdown_all()
+    }
+}
+
+impl WsSender for Sender {
+    fn send_dataframe<D>(&mut self, frame: &D) -> WebSocketResult<()>
+        where D: DataFrameTrait {
+
+        // Messages are sent as single frames, and control frames are never
+        // compressed
+        let opcode = Opcode::new(frame.opcode());
+        let compressor = match (self.compressor.as_mut(), opcode) {
+            (Some(compressor), Some(Opcode::Text)) |
+            (Some(compressor), Some(Opcode::Binary)) if frame.is_last() => compressor,
+            _ => return self.inner.send_dataframe(frame)
+        };
+
+        let compressed = DataFrame {
+            finished: true,
+            reserved: [true, false, false],
+            opcode: opcode.unwrap(),
+            data: compressor.compress(&frame.payload())
+        };
+
+        self.inner.send_dataframe(&compressed)
+    }
+}
+
+/// Receives frames from the server, decompressing messages that were sent
+/// compressed
+pub struct Receiver {
+    inner: ReceiverObj<WebSocketStream>,
+    decompressor: Option<Decompressor>
+}
+
+impl Receiver {
+    pub fn new(inner: ReceiverObj<WebSocketStream>,
+               decompressor: Option<Decompressor>) -> Receiver {
+        Receiver {
+            inner: inner,
+            decompressor: decompressor
+        }
+    }
+}
+
+impl WsReceiver<DataFrame> for Receiver {
+    fn recv_dataframe(&mut self) -> WebSocketResult<DataFrame> {
+        self.inner.recv_dataframe()
+    }
+
+    fn recv_message_dataframes(&mut self) -> WebSocketResult<Vec<DataFrame>> {
+        let frames = self.inner.recv_message_dataframes()?;
+
+        // Only the first frame of a compressed message has RSV1 set
+        let compressed = frames.first().map_or(false, |frame| frame.reserved[0]);
+        let decompressor = match self.decompressor.as_mut() {
+            Some(decompressor) if compressed => decompressor,
+            _ => return Ok(frames)
+        };
+
+        let mut payload = Vec::new();
+        for frame in &frames {
+            payload.extend_from_slice(&frame.data);
+        }
+
+        Ok(vec![DataFrame {
+            finished: true,
+            reserved: [false; 3],
+            opcode: frames[0].opcode,
+            data: decompressor.decompress(&payload)?
+        }])
+    }
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn messages_survive_compression() {
+    let deflate = Deflate::default();
+    let mut compressor = Compressor::new(&deflate);
+    let mut decompressor = Decompressor::new(&deflate);
+
+    // The second message refers back to the first one
+    for message in &[&b"hello hello hello hello"[..], &b"hello again"[..], &b""[..]] {
+        let compressed = compressor.compress(message);
+        assert!(!compressed.ends_with(&TRAILER));
+        assert_eq!(&decompressor.decompress(&compressed).unwrap()[..], *message);
+    }
+}
+
+#[test]
+fn deflate_parameters_are_accepted() {
+    let extension = |params: Vec<(&str, Option<&str>)>| Extension {
+        name: String::from(PERMESSAGE_DEFLATE),
+        params: params.into_iter().map(|(name, value)| {
+            Parameter::new(String::from(name), value.map(String::from))
+        }).collect()
+    };
+
+    let deflate = Deflate::accept(&extension(vec![
+        ("server_no_context_takeover", None),
+        ("client_no_context_takeover", None),
+        ("server_max_window_bits", Some("10"))
+    ])).unwrap();
+    assert!(deflate.server_no_context_takeover);
+    assert!(deflate.client_no_context_takeover);
+    assert_eq!(deflate.server_max_window_bits, Some(10));
+
+    assert!(Deflate::accept(&extension(vec![("client_max_window_bits", Some("9"))])).is_err());
+    assert!(Deflate::accept(&extension(vec![("client_max_window_bits", Some("15"))])).is_err());
+    assert!(Deflate::accept(&extension(vec![("server_max_window_bits", Some("20"))])).is_err());
+    assert!(Deflate::accept(&extension(vec![("unknown", None)])).is_err());
+    assert!(Deflate::accept(&extension(vec![("server_no_context_takeover", None),
+                                            ("server_no_context_takeover", None)])).is_err());
+
+    // As parsed from a header
+    let deflate = Deflate::accept(&extension(vec![("server_max_window_bits=9", None)]));
+    assert_eq!(deflate.unwrap().server_max_window_bits, Some(9));
+}
@@ -0,0 +1,443 @@
+//! The errors returned by a `Session`, and the exit codes of the `wsta`
+//! command line tool.
+//!
+//! Every class of error exits with a code of its own, so that scripts can
+//! tell a server that is down from credentials that have expired. The codes
+//! are listed in wsta.md.
+use std::error;
+use std::fmt;
+use std::io;
+
+use hyper;
+use hyper::error::ParseError;
+use hyper::status::StatusCode;
+
+use websocket::result::WebSocketError;
+
+/// Exit code used when the connection is lost, or reconnecting failed
+pub const EXIT_CONNECTION_LOST: i32 = 2;
+
+/// Exit code used when the server closes with a code outside 1000 - 1015
+pub const EXIT_OTHER_CLOSE_CODE: i32 = 3;
+
+/// Exit code used when a URL could not be parsed
+pub const EXIT_INVALID_URL: i32 = 4;
+
+/// Exit code used when the host name of the server could not be resolved
+pub const EXIT_DNS: i32 = 5;
+
+/// Exit code used when the server could not be reached
+pub const EXIT_CONNECT: i32 = 6;
+
+/// Exit code used when the TLS handshake failed
+pub const EXIT_TLS: i32 = 7;
+
+/// Exit code used when the WebSocket handshake failed
+pub const EXIT_HANDSHAKE: i32 = 8;
+
+/// Exit code used when logging in failed
+pub const EXIT_LOGIN: i32 = 9;
+
+/// Exit code used when a frame could not be sent
+pub const EXIT_SEND: i32 = 10;
+
+/// Exit code used when the proxy could not be reached, or refused to open
+/// a tunnel to the server
+pub const EXIT_PROXY: i32 = 11;
+
+/// Exit code used when connecting to the server, or to the proxy, took
+/// longer than `--connect-timeout`
+pub const EXIT_CONNECT_TIMEOUT: i32 = 12;
+
+/// Exit code used when the TLS and WebSocket handshakes took longer than
+/// `--handshake-timeout`
+pub const EXIT_HANDSHAKE_TIMEOUT: i32 = 13;
+
+/// Exit code used when nothing was received from the server for
+/// `--idle-timeout` seconds
+pub const EXIT_IDLE_TIMEOUT: i32 = 14;
+
+/// Exit code used when wsta is still running after `--timeout`, for example
+/// because `--count` messages were not received in time
+pub const EXIT_TIMEOUT: i32 = 15;
+
+/// Exit code used when a step of the `--script` failed
+pub const EXIT_SCRIPT_FAILED: i32 = 16;
+
+/// Exit code used when an option, or a value in the config file, is
+/// invalid
+pub const EXIT_INVALID_ARGUMENT: i32 = 17;
+
+/// Exit code used when a file such as the `--record` file could not be
+/// read or written, or reading stdin or writing to stdout failed
+pub const EXIT_IO: i32 = 18;
+
+/// Exit code used when `--listen` could not bind to its address
+pub const EXIT_BIND: i32 = 19;
+
+/// Exit code used when wsta is interrupted, as if it was ended by SIGINT
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+#[derive(Debug)]
+pub enum Error {
+
+    /// A URL could not be parsed. Holds the URL as it was given.
+    InvalidUrl(String, ParseError),
+
+    /// The host name of the server could not be resolved
+    Dns(String, io::Error),
+
+    /// The server could not be reached. Holds the URL of the server.
+    Connect(String, WebSocketError),
+
+    /// The proxy could not be reached, or refused to open a tunnel to the
+    /// server. Holds the proxy.
+    Proxy(String, io::Error),
+
+    /// Connecting to the server or the proxy took too long. Holds the URL
+    /// of the server and the timeout in seconds.
+    ConnectTimeout(String, u64),
+
+    /// The TLS and WebSocket handshakes took too long. Holds the URL of the
+    /// server and the timeout in seconds.
+    HandshakeTimeout(String, u64),
+
+    /// Nothing was received from the server for the given number of seconds
+    IdleTimeout(u64),
+
+    /// The TLS handshake with the server failed. Holds the URL of the server.
+    Tls(String, WebSocketError),
+
+    /// The TLS settings could not be set up. Holds the certificate or key
+    /// file that could not be loaded, if any.
+    Certificate(String, io::Error),
+
+    /// A `--pin` could not be parsed. Holds the pin as it was given.
+    InvalidPin(String),
+
+    /// The WebSocket upgrade request could not be sent, or the server did
+    /// not accept it
+    Handshake(WebSocketError),
+
+    /// The server did not select one of the subprotocols that were offered.
+    /// Holds the offered subprotocols, and the ones the server selected.
+    Subprotocol(Vec<String>, Vec<String>),
+
+    /// The login request could not be sent
+    Login(hyper::Error),
+
+    /// The server answered the login request with an error status. Holds
+    /// the login URL and the status.
+    LoginStatus(String, StatusCode),
+
+    /// The body of the login request could not be read. Holds the file it
+    /// was read from.
+    LoginData(String, io::Error),
+
+    /// The `--cookie-jar` could not be loaded or saved. Holds the file.
+    CookieJar(String, io::Error),
+
+    /// A step of the multi-step login failed. Holds the number of the step,
+    /// counting from 1, and why it failed.
+    LoginStep(usize, String),
+
+    /// The URL or a header of the upgrade request refers to a variable that
+    /// no login step extracted. Holds its name.
+    UnknownVariable(String),
+
+    /// The login response did not set any cookies
+    NoSessionCookie,
+
+    /// The login response has no token at the `--login-token` path. Holds
+    /// the path.
+    NoToken(String),
+
+    /// No OAuth2 access token could be fetched. Holds why.
+    OAuth(String),
+
+    /// The OAuth2 token cache could not be read or written. Holds the file.
+    TokenCache(String, io::Error),
+
+    /// A frame could not be sent
+    Send(WebSocketError),
+
+    /// A frame could not be received
+    WebSocket(WebSocketError),
+
+    /// The server closed the connection with the given close code and reason
+    Closed(Option<u16>, String),
+
+    /// The config file could not be parsed, or holds an invalid value.
+    /// Holds why.
+    Config(String),
+
+    /// An option, or a file given with one, is invalid. Holds why.
+    InvalidArgument(String),
+
+    /// Reading or writing failed. Holds what was being read or written, as
+    /// in "Could not open recording file 'session.jsonl'".
+    Io(String, io::Error),
+
+    /// Could not listen for clients. Holds the address.
+    Bind(String, io::Error)
+}
+
+impl Error {
+
+    /// The exit code of the command line tool for this error
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::InvalidUrl(..) => EXIT_INVALID_URL,
+            Error::Dns(..) => EXIT_DNS,
+            Error::Connect(..) => EXIT_CONNECT,
+            Error::Proxy(..) => EXIT_PROXY,
+            Error::ConnectTimeout(..) => EXIT_CONNECT_TIMEOUT,
+            Error::HandshakeTimeout(..) => EXIT_HANDSHAKE_TIMEOUT,
+            Error::IdleTimeout(_) => EXIT_IDLE_TIMEOUT,
+            Error::Tls(..) | Error::Certificate(..) |
+            Error::InvalidPin(_) => EXIT_TLS,
+            Error::Handshake(_) | Error::Subprotocol(..) => EXIT_HANDSHAKE,
+            Error::Login(_) | Error::LoginStatus(..) |
+            Error::LoginData(..) | Error::CookieJar(..) |
+            Error::LoginStep(..) | Error::UnknownVariable(_) |
+            Error::NoSessionCookie | Error::NoToken(_) |
+            Error::OAuth(_) | Error::TokenCache(..) => EXIT_LOGIN,
+            Error::Send(_) => EXIT_SEND,
+            Error::WebSocket(_) => EXIT_CONNECTION_LOST,
+            Error::Closed(code, _) => close_exit_code(code),
+            Error::Config(_) | Error::InvalidArgument(_) => EXIT_INVALID_ARGUMENT,
+            Error::Io(..) => EXIT_IO,
+            Error::Bind(..) => EXIT_BIND
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidUrl(ref url, _) => {
+                write!(f, "An error occured while parsing '{}' as a URL", url)
+            },
+            Error::Dns(ref host, _) => {
+                write!(f, "Could not resolve host '{}'", host)
+            },
+            Error::Connect(ref url, ref err) => {
+                write!(f, "An error occured while connecting to '{}'", url)?;
+                write_detail(f, err)
+            },
+            Error::Proxy(ref proxy, _) => {
+                write!(f, "Could not connect through proxy '{}'", proxy)
+            },
+            Error::ConnectTimeout(ref url, timeout) => {
+                write!(f, "Timed out connecting to '{}' after {} seconds",
+                       url, timeout)
+            },
+            Error::HandshakeTimeout(ref url, timeout) => {
+                write!(f, "The handshake with '{}' did not complete within {} \
+                           seconds", url, timeout)
+            },
+            Error::IdleTimeout(timeout) => {
+                write!(f, "Nothing was received from the server for {} seconds",
+                       timeout)
+            },
+            Error::Tls(ref url, ref err) => {
+                write!(f, "The TLS handshake with '{}' failed", url)?;
+                write_detail(f, err)
+            },
+            Error::Certificate(ref path, _) if path.is_empty() => {
+                write!(f, "Could not set up TLS")
+            },
+            Error::Certificate(ref path, _) => {
+                write!(f, "Could not load certificate or key from '{}'", path)
+            },
+            Error::InvalidPin(ref pin) => {
+                write!(f, "Invalid pin '{}'. Use sha256//BASE64, or a SHA-256 \
+                           fingerprint in hex", pin)
+            },
+            Error::Handshake(ref err) => {
+                write!(f, "The WebSocket handshake failed")?;
+                write_detail(f, err)
+            },
+            Error::Subprotocol(ref offered, ref selected) if selected.is_empty() => {
+                write!(f, "The server did not select any of the subprotocols \
+                           offered: {}", offered.join(", "))
+            },
+            Error::Subprotocol(ref offered, ref selected) => {
+                write!(f, "The server selected subprotocol '{}', which was not \
+                           offered (offered: {})", selected.join(", "),
+                       offered.join(", "))
+            },
+            Error::Login(_) => write!(f, "Error sending login request"),
+            Error::LoginStatus(ref url, status) => {
+                write!(f, "The login request to '{}' failed with {}", url, status)
+            },
+            Error::LoginData(ref file, _) => {
+                write!(f, "Could not read login data from '{}'", file)
+            },
+            Error::CookieJar(ref file, _) => {
+                write!(f, "Could not use the cookie jar '{}'", file)
+            },
+            Error::LoginStep(number, ref reason) => {
+                write!(f, "Login step {} failed: {}", number, reason)
+            },
+            Error::UnknownVariable(ref name) => {
+                write!(f, "No login step extracts the variable '{}'", name)
+            },
+            Error::NoSessionCookie => {
+                write!(f, "Attempted to fetch session cookie, but no cookies \
+                           were found in response's SetCookie header")
+            },
+            Error::NoToken(ref path) => {
+                write!(f, "The login response has no token at {}", path)
+            },
+            Error::OAuth(ref reason) => {
+                write!(f, "Failed to get an OAuth2 token: {}", reason)
+            },
+            Error::TokenCache(ref file, _) => {
+                write!(f, "Could not use the token cache '{}'", file)
+            },
+            Error::Send(ref err) => {
+                write!(f, "Failed to send frame")?;
+                write_detail(f, err)
+            },
+            Error::WebSocket(ref err) => {
+                write!(f, "Failed to receive frame")?;
+                write_detail(f, err)
+            },
+            Error::Closed(Some(code), ref reason) if !reason.is_empty() => {
+                write!(f, "Connection closed with code {}: {}", code, reason)
+            },
+            Error::Closed(Some(code), _) => {
+                write!(f, "Connection closed with code {}", code)
+            },
+            Error::Closed(None, _) => {
+                write!(f, "Connection closed without a close code")
+            },
+            Error::Config(ref reason) => write!(f, "CONFIG ERROR: {}", reason),
+            Error::InvalidArgument(ref reason) => write!(f, "{}", reason),
+            Error::Io(ref what, _) => write!(f, "{}", what),
+            Error::Bind(ref address, _) => {
+                write!(f, "An error occured while listening on '{}'", address)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::InvalidUrl(_, ref err) => Some(err),
+            Error::Dns(_, ref err) |
+            Error::Certificate(_, ref err) |
+            Error::Proxy(_, ref err) |
+            Error::LoginData(_, ref err) |
+            Error::CookieJar(_, ref err) |
+            Error::TokenCache(_, ref err) |
+            Error::Io(_, ref err) |
+            Error::Bind(_, ref err) => Some(err),
+            Error::Login(ref err) => Some(err),
+            Error::Connect(_, ref err) |
+            Error::Tls(_, ref err) |
+            Error::Handshake(ref err) |
+            Error::Send(ref err) |
+            Error::WebSocket(ref err) => websocket_source(err),
+            Error::ConnectTimeout(..) | Error::HandshakeTimeout(..) |
+            Error::IdleTimeout(_) |
+            Error::InvalidPin(_) | Error::Subprotocol(..) |
+            Error::LoginStatus(..) | Error::LoginStep(..) | Error::UnknownVariable(_) |
+            Error::NoSessionCookie | Error::NoToken(_) | Error::OAuth(_) |
+            Error::Closed(..) | Error::Config(_) |
+            Error::InvalidArgument(_) => None
+        }
+    }
+}
+
+impl From<WebSocketError> for Error {
+    fn from(err: WebSocketError) -> Error {
+        Error::WebSocket(err)
+    }
+}
+
+/// Describe `err` along with the chain of errors that caused it, as in
+/// "Error sending login request: Connection refused (os error 111)"
+pub fn describe(err: &dyn error::Error) -> String {
+    let mut description = format!("{}", err);
+    let mut source = err.source();
+
+    while let Some(err) = source {
+        description.push_str(&format!(": {}", err));
+        source = err.source();
+    }
+
+    description
+}
+
+/// The exit code to use after the server closed with `code`. A close frame
+/// without a code is treated as 1005, which is reserved for that purpose.
+pub fn close_exit_code(code: Option<u16>) -> i32 {
+    match code.unwrap_or(1005) {
+        // A normal closure
+        1000 => 0,
+        code @ 1001..=1015 => code as i32 - 900,
+        _ => EXIT_OTHER_CLOSE_CODE
+    }
+}
+
+/// The error that caused a WebSocketError, if there is one. The
+/// WebSocketError itself only describes which kind of error it wraps.
+fn websocket_source(err: &WebSocketError) -> Option<&(dyn error::Error + 'static)> {
+    match *err {
+        WebSocketError::IoError(ref err) => Some(err),
+        WebSocketError::HttpError(ref err) => Some(err),
+        WebSocketError::UrlError(ref err) => Some(err),
+        WebSocketError::SslError(ref err) => Some(err),
+        WebSocketError::Utf8Error(ref err) => Some(err),
+        WebSocketError::WebSocketUrlError(ref err) => Some(err),
+        _ => None
+    }
+}
+
+/// Append the details of a WebSocketError that has no underlying error
+fn write_detail(f: &mut fmt::Formatter, err: &WebSocketError) -> fmt::Result {
+    match *err {
+        WebSocketError::ProtocolError(detail) |
+        WebSocketError::RequestError(detail) |
+        WebSocketError::ResponseError(detail) |
+        WebSocketError::DataFrameError(detail) => write!(f, ": {}", detail),
+        WebSocketError::NoDataAvailable => {
+            write!(f, ": the connection was closed")
+        },
+        _ => Ok(())
+    }
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn close_codes_map_to_exit_codes() {
+    assert_eq!(close_exit_code(Some(1000)), 0);
+    assert_eq!(close_exit_code(Some(1001)), 101);
+    assert_eq!(close_exit_code(Some(1011)), 111);
+    assert_eq!(close_exit_code(None), 105);
+    assert_eq!(close_exit_code(Some(4000)), EXIT_OTHER_CLOSE_CODE);
+}
+
+#[test]
+fn causes_are_described() {
+    let cause = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+    let err = Error::Connect(String::from("ws://localhost"),
+                             WebSocketError::IoError(cause));
+
+    assert_eq!(describe(&err), "An error occured while connecting to \
+                                'ws://localhost': refused");
+    assert_eq!(err.exit_code(), EXIT_CONNECT);
+
+    let err = Error::Handshake(WebSocketError::ResponseError("Bad status"));
+    assert_eq!(describe(&err), "The WebSocket handshake failed: Bad status");
+
+    let cause = io::Error::new(io::ErrorKind::AddrInUse, "in use");
+    let err = Error::Bind(String::from("localhost:8080"), cause);
+    assert_eq!(describe(&err), "An error occured while listening on \
+                                'localhost:8080': in use");
+    assert_eq!(err.exit_code(), EXIT_BIND);
+}
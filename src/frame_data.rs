@@ -1,5 +1,5 @@
 /// Holds a frame of either utf8 encoded or binary data.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct FrameData {
     pub utf8: Option<String>,
     pub binary: Option<Vec<u8>>
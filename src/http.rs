@@ -1,23 +1,84 @@
+//! The HTTP requests made before connecting: the `--login` request, the
+//! multi-step login configured with `login_steps`, and the OAuth2 token
+//! requests made by `oauth`.
+//!
+//! Each login step can extract values from its response, which later steps,
+//! the URL and the headers of the upgrade request refer to as `{{name}}`.
 use std::io;
-use std::io::Write;
-use std::process::exit;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::collections::HashMap;
 
-use cookie::Cookie as CookiePair;
+use regex::Regex;
+use rustc_serialize::json::Json;
 
 use hyper::Client;
 use hyper::Url;
-use hyper::net::{HttpsConnector, Openssl};
-use hyper::header::{Headers, SetCookie, Cookie};
+use hyper::header::{Headers, SetCookie, ContentType};
+use hyper::method::Method;
 use hyper::status::StatusCode;
-use hyper::client::RedirectPolicy;
+use hyper::client::{RedirectPolicy, Response};
 
+use cookies::CookieJar;
+use error::Error;
+use json_path::JsonPath;
 use options::Options;
+use proxy::{ProxyConnector, DirectConnector, proxy_for};
+use session::seconds;
+use tls::Tls;
+use token::Token;
 
-pub fn fetch_session_cookie(options: &Options) -> Option<Cookie> {
+/// A request of a multi-step login, as configured in `login_steps`
+#[derive(Debug, Clone)]
+pub struct LoginStep {
 
-    // Create a client.
-    let mut client = Client::new();
-    log!(3, "Created HTTP client: {:?}", client);
+    /// The HTTP method. Defaults to POST when there is data, and GET
+    /// otherwise.
+    pub method: String,
+
+    pub url: String,
+
+    /// The body of the request, if any
+    pub data: String,
+
+    pub headers: Vec<String>,
+
+    /// The values to extract from the response
+    pub extract: Vec<Extract>
+}
+
+/// A value to extract from the response to a login step
+#[derive(Debug, Clone)]
+pub struct Extract {
+
+    /// The name later steps and the upgrade request refer to the value by
+    pub name: String,
+
+    pub from: ExtractFrom
+}
+
+/// Where in a response to find an extracted value
+#[derive(Debug, Clone)]
+pub enum ExtractFrom {
+
+    /// The value of a response header
+    Header(String),
+
+    /// The value at a path in a JSON response body
+    Json(JsonPath),
+
+    /// The first group of a regular expression matching the response body,
+    /// or the whole match if it has no groups
+    Regex(Regex)
+}
+
+/// Send the login request to `options.login_url` and store the session
+/// cookie of the response in `jar`. With `options.login_token`, the token in
+/// the response is returned instead, and the response need not set a
+/// cookie. Errors are returned, so the caller can decide whether the failure
+/// is fatal.
+pub fn log_in(options: &Options,
+              jar: &mut CookieJar) -> Result<Option<Token>, Error> {
 
     // Parse string as url and handle ParseErrors
     let url = match Url::parse(&options.login_url) {
@@ -28,51 +89,344 @@ pub fn fetch_session_cookie(options: &Options) -> Option<Cookie> {
         }
         Err(err) => {
             log!(1, "Error object: {:?}", err);
-            stderr!("Failed to parse url '{}': {}", &options.login_url, &err);
-            exit(1);
+            return Err(Error::InvalidUrl(options.login_url.clone(), err));
         }
     };
 
-    // Wrap with TLS if needed
-    if url.scheme() == "https" {
-        log!(3, "Scheme is https");
+    let body = login_body(options)?;
+    let method = login_method(&options.login_method, body.is_some());
+    let headers = login_headers(options, &options.login_headers, body.as_ref());
 
-        let https_connector = HttpsConnector::new(Openssl::default());
-        log!(3, "Created https_connector: {:?}", https_connector);
+    let mut response = send_login_request(options, "Authenticate", method, &url,
+                                          headers, body, jar)?;
 
-        client = Client::with_connector(https_connector);
-        log!(3, "Set client to be TLS wrapped client: {:?}", client);
+    if response.status.is_client_error() || response.status.is_server_error() {
+        return Err(Error::LoginStatus(options.login_url.clone(), response.status));
     }
 
-    // Only redirect if requested - otherwise it is really confusing
-    if !options.follow_redirect {
-        client.set_redirect_policy(RedirectPolicy::FollowNone);
-        log!(3, "Set client to not follow redirects: {:?}", client);
+    if !options.login_token.is_empty() {
+        let path = JsonPath::parse(&options.login_token)
+            .map_err(|_| Error::NoToken(options.login_token.clone()))?;
+
+        let mut text = String::new();
+        response.read_to_string(&mut text).map_err(|err| Error::Login(err.into()))?;
+        log!(4, "Response body: {}", text);
+
+        return Token::from_response(&text, &path)
+            .map(Some)
+            .ok_or(Error::NoToken(options.login_token.clone()));
+    }
+
+    if let Some(set_cookie) = response.headers.get::<SetCookie>() {
+        jar.set_login_cookies(set_cookie);
+        Ok(None)
+    } else {
+        log!(1, "No SetCookie header found in response");
+        Err(Error::NoSessionCookie)
+    }
+}
+
+/// Run the steps of `options.login_steps` in order, storing the cookies
+/// they set in `jar`.
+///
+/// # Returns
+/// Returns the values extracted from the responses, by name.
+pub fn run_login_steps(options: &Options,
+                       jar: &mut CookieJar) -> Result<HashMap<String, String>, Error> {
+
+    let mut variables = HashMap::new();
+
+    for (index, step) in options.login_steps.iter().enumerate() {
+        let number = index + 1;
+        let unknown = |name| Error::LoginStep(number, format!("unknown variable '{}'", name));
+
+        let raw_url = expand(&step.url, &variables, true).map_err(&unknown)?;
+        let url = Url::parse(&raw_url)
+            .map_err(|err| Error::InvalidUrl(raw_url.clone(), err))?;
+
+        let body = if step.data.is_empty() {
+            None
+        } else {
+            Some(expand(&step.data, &variables, false).map_err(&unknown)?.into_bytes())
+        };
+
+        let step_headers = step.headers.iter()
+            .map(|header| expand(header, &variables, false))
+            .collect::<Result<Vec<String>, String>>()
+            .map_err(&unknown)?;
+
+        let method = login_method(&step.method, body.is_some());
+        let headers = login_headers(options, &step_headers, body.as_ref());
+
+        let title = format!("Login step {}", number);
+        let mut response = send_login_request(options, &title, method, &url,
+                                              headers, body, jar)?;
+
+        if response.status.is_client_error() || response.status.is_server_error() {
+            return Err(Error::LoginStep(number, format!("{} answered {}",
+                                                         url, response.status)));
+        }
+
+        // The body is only read if a value is extracted from it
+        let mut text = String::new();
+        if step.extract.iter().any(|extract| !is_header(extract)) {
+            response.read_to_string(&mut text)
+                .map_err(|err| Error::LoginStep(number, format!("{}", err)))?;
+            log!(4, "Response body: {}", text);
+        }
+
+        for extract in &step.extract {
+            let value = extract_value(&extract.from, &response.headers, &text)
+                .ok_or_else(|| Error::LoginStep(number, describe_missing(extract)))?;
+
+            log!(2, "Extracted {}: {}", extract.name, value);
+            variables.insert(extract.name.clone(), value);
+        }
+    }
+
+    Ok(variables)
+}
+
+/// Fill the values of `variables` into the `{{name}}` placeholders of
+/// `template`. Values are percent-encoded if `encode` is set, as they are
+/// for URLs.
+///
+/// # Returns
+/// Returns the name of the first placeholder without a value as the error.
+pub fn expand(template: &str,
+              variables: &HashMap<String, String>,
+              encode: bool) -> Result<String, String> {
+
+    let mut expanded = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break
+        };
+
+        let name = rest[start + 2..end].trim();
+        let value = variables.get(name).ok_or_else(|| String::from(name))?;
+
+        expanded.push_str(&rest[..start]);
+        if encode {
+            expanded.push_str(&percent_encode(value));
+        } else {
+            expanded.push_str(value);
+        }
+
+        rest = &rest[end + 2..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986
+pub fn percent_encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' |
+        b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte)
+    }).collect()
+}
+
+/// Send a login request along with the cookies in `jar` for `url`, and
+/// store the cookies set by the response. Requests and responses are
+/// printed under `title` with `options.print_headers`.
+pub fn send_login_request(options: &Options,
+                          title: &str,
+                          method: Method,
+                          url: &Url,
+                          mut headers: Headers,
+                          body: Option<Vec<u8>>,
+                          jar: &mut CookieJar) -> Result<Response, Error> {
+
+    let client = login_client(options, url)?;
+
+    if let Some(cookie) = jar.header(url) {
+        headers.set(cookie);
+    }
+
+    if options.print_headers {
+        print_headers(&format!("{} request ({} {})", title, method, url),
+                      &headers, None);
     }
 
     // Create RequestBuilder
-    let request = client.get(url);
+    let mut request = client.request(method, url.clone()).headers(headers);
     log!(3, "Created RequestBuilder");
 
+    if let Some(ref body) = body {
+        request = request.body(&body[..]);
+        log!(3, "Added body of {} bytes", body.len());
+    }
+
     // Create and send an outgoing request.
     match request.send() {
         Ok(res) => {
             log!(2, "Received response: {:?}", res);
 
             if options.print_headers {
-                print_headers("Authenticate response", &res.headers, None);
+                print_headers(&format!("{} response", title), &res.headers,
+                              Some(res.status));
+            }
+
+            if let Some(set_cookie) = res.headers.get::<SetCookie>() {
+                jar.store(url, set_cookie);
             }
 
-            return extract_cookie(&res.headers);
+            Ok(res)
         },
         Err(err) => {
             log!(1, "Error: {:?}", err);
-            stderr!("Error sending login request: {}", &err);
-            exit(1);
+            Err(Error::Login(err))
         }
     }
 }
 
+/// An HTTP client for login requests to `url`. Connecting gives up after
+/// `options.connect_timeout` seconds.
+fn login_client(options: &Options, url: &Url) -> Result<Client, Error> {
+    let timeout = seconds(options.connect_timeout);
+
+    // Tunnel through a proxy if needed, which takes care of TLS as well.
+    // Otherwise, wrap with TLS if needed.
+    let mut client = if let Some(proxy) = proxy_for(options, url)? {
+        log!(3, "Using proxy {}", proxy);
+
+        let tls = Tls::from_options(options)?;
+        Client::with_connector(ProxyConnector::new(proxy, tls, timeout))
+    } else if url.scheme() == "https" {
+        log!(3, "Scheme is https");
+
+        let tls = Tls::from_options(options)?;
+        Client::with_connector(DirectConnector::new(Some(tls), timeout))
+    } else {
+        Client::with_connector(DirectConnector::new(None, timeout))
+    };
+    log!(3, "Created HTTP client: {:?}", client);
+
+    // Only redirect if requested - otherwise it is really confusing
+    if !options.follow_redirect {
+        client.set_redirect_policy(RedirectPolicy::FollowNone);
+        log!(3, "Set client to not follow redirects: {:?}", client);
+    }
+
+    Ok(client)
+}
+
+fn is_header(extract: &Extract) -> bool {
+    match extract.from {
+        ExtractFrom::Header(_) => true,
+        _ => false
+    }
+}
+
+/// Find an extracted value in the headers or the body of a response
+fn extract_value(from: &ExtractFrom, headers: &Headers, body: &str) -> Option<String> {
+    match *from {
+        ExtractFrom::Header(ref name) => {
+            headers.get_raw(name)
+                .and_then(|values| values.first())
+                .map(|value| String::from_utf8_lossy(value).trim().to_string())
+        },
+        ExtractFrom::Json(ref path) => {
+            let json = Json::from_str(body).ok()?;
+
+            match path.find(&json) {
+                Some(&Json::String(ref value)) => Some(value.clone()),
+                Some(&Json::Null) | None => None,
+                Some(value) => Some(value.to_string())
+            }
+        },
+        ExtractFrom::Regex(ref regex) => {
+            regex.captures(body).and_then(|captures| {
+                captures.at(1).or(captures.at(0)).map(String::from)
+            })
+        }
+    }
+}
+
+fn describe_missing(extract: &Extract) -> String {
+    match extract.from {
+        ExtractFrom::Header(ref name) => {
+            format!("{}: the response has no {} header", extract.name, name)
+        },
+        ExtractFrom::Json(ref path) => {
+            format!("{}: the response has no value at {}", extract.name, path)
+        },
+        ExtractFrom::Regex(ref regex) => {
+            format!("{}: the response does not match /{}/", extract.name, regex)
+        }
+    }
+}
+
+/// The body of the login request, from `options.login_data` or
+/// `options.login_data_file`
+fn login_body(options: &Options) -> Result<Option<Vec<u8>>, Error> {
+    if !options.login_data_file.is_empty() {
+        let mut body = Vec::new();
+
+        return File::open(&options.login_data_file)
+            .and_then(|mut file| file.read_to_end(&mut body))
+            .map(|_| Some(body))
+            .map_err(|err| Error::LoginData(options.login_data_file.clone(), err));
+    }
+
+    if options.login_data.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(options.login_data.clone().into_bytes()))
+    }
+}
+
+/// The method of a login request. Without a `method`, data is POSTed, and a
+/// request without data is a GET.
+fn login_method(method: &str, has_body: bool) -> Method {
+    match method.to_uppercase().parse() {
+        Ok(method) => method,
+        Err(_) if has_body => Method::Post,
+        Err(_) => Method::Get
+    }
+}
+
+/// The headers of a login request, which are `extra_headers` along with the
+/// -H headers if `options.login_send_headers` is set. The content type of a
+/// body is guessed, unless it was given.
+fn login_headers(options: &Options,
+                 extra_headers: &Vec<String>,
+                 body: Option<&Vec<u8>>) -> Headers {
+
+    let mut headers = Headers::new();
+
+    if options.login_send_headers && !options.headers.is_empty() {
+        add_headers(&mut headers, &options.headers);
+    }
+
+    if !extra_headers.is_empty() {
+        add_headers(&mut headers, extra_headers);
+    }
+
+    if let Some(body) = body {
+        if !headers.has::<ContentType>() {
+            headers.set(content_type_for(body));
+        }
+    }
+
+    headers
+}
+
+/// Guess the content type of a login body, which is either JSON or form
+/// data such as `user=name&password=secret`
+fn content_type_for(body: &[u8]) -> ContentType {
+    match body.iter().find(|byte| !(**byte as char).is_whitespace()) {
+        Some(&b'{') | Some(&b'[') => ContentType::json(),
+        _ => ContentType::form_url_encoded()
+    }
+}
+
 pub fn print_headers(title: &str, headers: &Headers,
                      status: Option<StatusCode>) {
     stderr!("{}", title);
@@ -85,38 +439,88 @@ pub fn print_headers(title: &str, headers: &Headers,
     stderr!("{}\n", headers);
 }
 
-/// Looks up the SetCookie header of the Headers set, and maps
-/// those headers into a consumable set of CookiePairs. It will
-/// return None of no SetCookie header was found.
-fn extract_cookie(headers: &Headers) -> Option<Cookie> {
+/// Adds raw `key:value` headers, as passed on the command line, to the
+/// provided set of Headers. Headers not containing a colon are skipped.
+pub fn add_headers(headers: &mut Headers, raw_headers: &Vec<String>) {
 
-    let set_cookie_header = headers.get::<SetCookie>();
-    log!(3, "Found SetCookie header: {:?}", set_cookie_header);
+    log!(2, "Adding headers: {:?}", raw_headers);
+    for header in raw_headers {
 
-    match set_cookie_header {
-        Some(header) => {
+        // Only process the header if it is a valid "key: value" header
+        if header.contains(':') {
 
-            // The name:value cookies
-            let mut cookies = Vec::new();
+            // Split by first colon into [key, value]
+            let split = header.splitn(2, ':').collect::<Vec<&str>>();
+            log!(3, "Split header: {:?}", split);
 
-            // Iterate over cookies in SetCookie header and re-map them
-            for cookie in header.as_slice() {
+            let key = split[0];
+            log!(3, "Key is: {}", key);
 
-                let pair = CookiePair::new(
-                    cookie.name.to_string(),
-                    cookie.value.to_string()
-                );
+            let val = split[1].to_string().into_bytes();
+            log!(3, "Val is: {:?} (bytes)", val);
 
-                log!(3, "Created CookiePair: {:?}", pair);
-                cookies.push(pair);
-            }
-
-            Some(Cookie(cookies))
-        },
-        _ => {
-            log!(1, "No SetCookie header found in response");
-            None
+            // Write raw (untyped) header
+            headers.set_raw(format!("{}", key), vec![val]);
+            log!(2, "Wrote new header. Headers are now: {:?}", headers);
+        } else {
+            stderr!("Invalid header: {}. Must contain a colon (:)", header);
         }
     }
 }
 
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn login_requests_are_built_from_options() {
+    let mut options = Options::new();
+    assert_eq!(login_method("", false), Method::Get);
+    assert_eq!(login_method("", true), Method::Post);
+    assert_eq!(login_method("put", true), Method::Put);
+
+    assert_eq!(content_type_for(b"  {\"user\": \"name\"}"), ContentType::json());
+    assert_eq!(content_type_for(b"user=name"), ContentType::form_url_encoded());
+
+    options.headers = vec![String::from("X-Upgrade: only")];
+    let login = vec![String::from("Content-Type: text/plain")];
+    let headers = login_headers(&options, &login, Some(&b"{}".to_vec()));
+    assert_eq!(headers.get_raw("Content-Type"), Some(&[b" text/plain".to_vec()][..]));
+    assert!(headers.get_raw("X-Upgrade").is_none());
+
+    options.login_send_headers = true;
+    assert!(login_headers(&options, &Vec::new(), None).get_raw("X-Upgrade").is_some());
+}
+
+#[test]
+fn variables_are_expanded() {
+    let mut variables = HashMap::new();
+    variables.insert(String::from("token"), String::from("a b&c"));
+
+    assert_eq!(expand("Bearer {{token}}", &variables, false).unwrap(), "Bearer a b&c");
+    assert_eq!(expand("/ws?token={{ token }}", &variables, true).unwrap(),
+               "/ws?token=a%20b%26c");
+    assert_eq!(expand("{\"json\": {}}", &variables, false).unwrap(), "{\"json\": {}}");
+    assert_eq!(expand("{{csrf}}", &variables, false).unwrap_err(), "csrf");
+}
+
+#[test]
+fn values_are_extracted_from_responses() {
+    let mut headers = Headers::new();
+    headers.set_raw("X-Csrf-Token", vec![b" abc".to_vec()]);
+    let body = r#"{"access_token": "xyz", "expires_in": 60} <input value="def">"#;
+
+    let from_json = |path| ExtractFrom::Json(JsonPath::parse(path).unwrap());
+    let from_regex = |regex| ExtractFrom::Regex(Regex::new(regex).unwrap());
+
+    let extract = |from| extract_value(&from, &headers, body);
+    assert_eq!(extract(ExtractFrom::Header(String::from("x-csrf-token"))),
+               Some(String::from("abc")));
+    assert_eq!(extract(from_regex(r#"value="([^"]+)""#)), Some(String::from("def")));
+    assert_eq!(extract(from_regex("access_\\w+")), Some(String::from("access_token")));
+    assert_eq!(extract(from_json(".access_token")), None);
+
+    let json = r#"{"access_token": "xyz", "expires_in": 60}"#;
+    let extract = |from| extract_value(&from, &headers, json);
+    assert_eq!(extract(from_json(".access_token")), Some(String::from("xyz")));
+    assert_eq!(extract(from_json(".expires_in")), Some(String::from("60")));
+    assert_eq!(extract(from_json(".missing")), None);
+}
@@ -0,0 +1,134 @@
+//! Paths into JSON values, such as `.items[0].id`, used to pick values out
+//! of login responses and frames received by scripts.
+use std::fmt;
+
+use rustc_serialize::json::Json;
+
+/// A step of a path into a JSON value, such as `.items` or `[0]`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Segment {
+    Key(String),
+    Index(usize)
+}
+
+/// A path into a JSON value. The path `.` is the whole value.
+#[derive(Debug, PartialEq, Clone)]
+pub struct JsonPath {
+    segments: Vec<Segment>
+}
+
+impl JsonPath {
+
+    /// Parse a path such as `.items[0].id`. The error describes what is
+    /// wrong with the path.
+    pub fn parse(text: &str) -> Result<JsonPath, String> {
+        let invalid = || format!("invalid path '{}'. Use paths such as \
+                                  .items[0].id", text);
+
+        if !text.starts_with('.') {
+            return Err(invalid());
+        }
+
+        let mut segments = Vec::new();
+        let mut rest = &text[1..];
+
+        while !rest.is_empty() {
+            if rest.starts_with('[') {
+                let end = rest.find(']').ok_or_else(&invalid)?;
+                let index = rest[1..end].parse().map_err(|_| invalid())?;
+
+                segments.push(Segment::Index(index));
+                rest = &rest[end + 1..];
+            } else {
+                if rest.starts_with('.') {
+                    rest = &rest[1..];
+                }
+
+                let end = rest.find(|c| c == '.' || c == '[').unwrap_or(rest.len());
+                if end == 0 {
+                    return Err(invalid());
+                }
+
+                segments.push(Segment::Key(String::from(&rest[..end])));
+                rest = &rest[end..];
+            }
+        }
+
+        Ok(JsonPath { segments: segments })
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// The path to the `key` next to the value at this path, such as
+    /// `.data.expires_in` for `.data.access_token`. The whole value has
+    /// nothing next to it.
+    pub fn sibling(&self, key: &str) -> Option<JsonPath> {
+        let (_, parent) = self.segments.split_last()?;
+
+        let mut segments = parent.to_vec();
+        segments.push(Segment::Key(String::from(key)));
+
+        Some(JsonPath { segments: segments })
+    }
+
+    /// The value at this path in `json`, if there is one
+    pub fn find<'a>(&self, json: &'a Json) -> Option<&'a Json> {
+        let mut value = Some(json);
+
+        for segment in &self.segments {
+            value = match (value, segment) {
+                (Some(&Json::Object(ref object)), &Segment::Key(ref key)) => {
+                    object.get(key)
+                },
+                (Some(&Json::Array(ref array)), &Segment::Index(index)) => {
+                    array.get(index)
+                },
+                _ => None
+            };
+        }
+
+        value
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.segments.is_empty() {
+            return write!(f, ".");
+        }
+
+        for segment in &self.segments {
+            match *segment {
+                Segment::Key(ref key) => write!(f, ".{}", key)?,
+                Segment::Index(index) => write!(f, "[{}]", index)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn paths_find_values() {
+    let path = JsonPath::parse(".items[1].id").unwrap();
+    assert_eq!(path.segments(), &[Segment::Key(String::from("items")),
+                                  Segment::Index(1),
+                                  Segment::Key(String::from("id"))]);
+    assert_eq!(path.to_string(), ".items[1].id");
+
+    let json = Json::from_str(r#"{"items": [{"id": 1}, {"id": 2}]}"#).unwrap();
+    assert_eq!(path.find(&json), Some(&Json::U64(2)));
+    assert_eq!(JsonPath::parse(".items[2]").unwrap().find(&json), None);
+    assert_eq!(JsonPath::parse(".").unwrap().find(&json), Some(&json));
+
+    assert_eq!(path.sibling("name").unwrap().to_string(), ".items[1].name");
+    assert_eq!(JsonPath::parse(".").unwrap().sibling("name"), None);
+
+    assert!(JsonPath::parse("items").is_err());
+    assert!(JsonPath::parse(".items[x]").is_err());
+    assert!(JsonPath::parse(".items..id").is_err());
+}
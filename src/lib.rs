@@ -0,0 +1,61 @@
+//! `wsta` - The WebSocket Transfer Agent, as a library
+//!
+//! The `wsta` command line tool is a thin layer on top of this crate. A
+//! [`Session`](session/struct.Session.html) connects to a server exactly
+//! like the tool does, using the same [options](options/struct.Options.html)
+//! for the login cookie, custom headers and so on, which makes it useful in
+//! tests that need to talk to the same servers.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! extern crate wsta;
+//!
+//! use wsta::{Options, Session};
+//!
+//! fn main() {
+//!     let mut options = Options::new();
+//!     options.url = String::from("ws://echo.websocket.org");
+//!     options.headers.push(String::from("Authorization: Bearer token"));
+//!
+//!     let mut session = Session::connect(&options).unwrap();
+//!     session.send_text("hello").unwrap();
+//!
+//!     let reply = session.receive().unwrap();
+//!     assert_eq!(&reply.payload[..], b"hello");
+//!
+//!     session.close(1000, "").unwrap();
+//! }
+//! ```
+
+extern crate websocket;
+extern crate hyper;
+extern crate openssl;
+extern crate openssl_sys;
+extern crate openssl_verify;
+extern crate cookie;
+extern crate config;
+extern crate xdg;
+extern crate rustc_serialize;
+extern crate flate2;
+extern crate regex;
+
+// Needs to be imported first because of log! macro
+#[macro_use]
+pub mod log;
+pub mod conf;
+pub mod cookies;
+pub mod deflate;
+pub mod error;
+pub mod http;
+pub mod json_path;
+pub mod oauth;
+pub mod options;
+pub mod proxy;
+pub mod session;
+pub mod tls;
+pub mod token;
+
+pub use error::Error;
+pub use options::Options;
+pub use session::Session;
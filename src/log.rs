@@ -1,11 +1,13 @@
 static mut log_level: u8 = 0;
 
+#[macro_export]
 macro_rules! stderr {
     ( $( $msg:tt )* ) => {{
         writeln!(io::stderr(), $($msg)*).unwrap();
     }}
 }
 
+#[macro_export]
 macro_rules! log {
     // No format string
     ($loudness:expr, $msg:expr ) => {{
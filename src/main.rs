@@ -15,26 +15,57 @@
 //! # Exit codes
 //! | Code | Reason                                      |
 //! |------|---------------------------------------------|
-//! | 1    | Irrecoverable error during normal operation |
-//! | 2    | Websocket stream was closed unexpectedly    |
+//! | 0    | The server closed with code 1000            |
+//! | 2    | Websocket stream was closed unexpectedly,   |
+//! |      | or all reconnection attempts failed         |
+//! | 3    | The server closed with a code outside       |
+//! |      | 1000 - 1015                                 |
+//! | 4    | A URL could not be parsed                   |
+//! | 5    | The host name could not be resolved         |
+//! | 6    | The server could not be reached             |
+//! | 7    | The TLS handshake failed, or a certificate  |
+//! |      | or key could not be loaded                  |
+//! | 8    | The WebSocket handshake failed              |
+//! | 9    | Logging in failed                           |
+//! | 10   | A frame could not be sent                   |
+//! | 11   | The proxy could not be reached, or refused  |
+//! |      | to open a tunnel to the server              |
+//! | 12   | Connecting took longer than                 |
+//! |      | --connect-timeout                           |
+//! | 13   | The handshakes took longer than             |
+//! |      | --handshake-timeout                         |
+//! | 14   | Nothing was received for --idle-timeout     |
+//! | 15   | Still running after --timeout               |
+//! | 16   | A step of the --script failed               |
+//! | 17   | An option is invalid, or the config file    |
+//! |      | could not be parsed                         |
+//! | 18   | A --record, --replay or --script file could |
+//! |      | not be read or written, or reading stdin or |
+//! |      | writing to stdout failed                    |
+//! | 19   | --listen could not bind to its address      |
+//! | 101- | The server closed with code 1001 - 1015.    |
+//! | 115  | The exit code is the close code - 900       |
+//! | 130  | Interrupted by SIGINT or SIGTERM            |
 
+// Needs to be imported first because of log! macro
+#[macro_use]
+extern crate wsta;
 extern crate websocket;
 extern crate argparse;
-extern crate hyper;
-extern crate cookie;
-extern crate config;
-extern crate xdg;
+extern crate rustc_serialize;
+extern crate ctrlc;
+extern crate regex;
 
-// Needs to be imported first because of log! macro
-#[macro_use]
-mod log;
-mod conf;
 mod args;
 mod frame_data;
 mod program;
-mod http;
+mod server;
+mod relay;
 mod ws;
-mod options;
+mod record;
+mod output;
+mod close;
+mod script;
 
 use argparse::*;
 use std::io;
@@ -42,8 +73,12 @@ use std::io::Write;
 use std::process::exit;
 use std::str::from_utf8;
 
+use wsta::{conf, log, oauth};
+use wsta::Options;
+use wsta::error::EXIT_INVALID_ARGUMENT;
+use wsta::json_path::JsonPath;
+
 use args::get_profile;
-use options::Options;
 
 /// The main entry point of the app.
 /// Parses command line options and starts the main program.
@@ -61,7 +96,8 @@ fn main() {
     let parsed_profile_log = format!("Parsed profile as: {:?}", &profile);
 
     // Read config file
-    let config = conf::read_conf_file(profile);
+    let config = conf::read_conf_file(profile.clone())
+        .unwrap_or_else(|err| program::exit_with(err));
 
     // Prepare log of conf until we have parsed verbosity
     let parsed_conf_log = format!("Parsed config file as: {:?}", &config);
@@ -69,7 +105,8 @@ fn main() {
     // Get default options from config if config exists,
     // else use global defaults
     let mut options = match config {
-        Some(conf) => Options::build_from_config(&conf),
+        Some(conf) => Options::build_from_config(&conf)
+            .unwrap_or_else(|err| program::exit_with(err)),
         None => Options::new()
     };
 
@@ -87,9 +124,27 @@ fn main() {
             .add_argument("url", Store,
                         "URL of the server to connect with");
 
+        ap.refer(&mut options.listen)
+            .metavar("ADDRESS")
+            .add_option(&["--listen"], Store,
+                        "accept WebSocket clients on ADDRESS instead of connecting to URL");
+
+        ap.refer(&mut options.relay)
+            .add_option(&["--relay"], StoreTrue,
+                        "with --listen, relay clients to URL and print all frames");
+
         ap.refer(&mut options.headers)
             .add_option(&["-H", "--header"], Collect,
-                        "add headers to any HTTP request made");
+                        "add headers to the WebSocket upgrade request");
+
+        ap.refer(&mut options.subprotocols)
+            .metavar("NAME")
+            .add_option(&["--subprotocol"], Collect,
+                        "offer a WebSocket subprotocol, in order of preference");
+
+        ap.refer(&mut options.no_compression)
+            .add_option(&["--no-compression"], StoreTrue,
+                        "do not offer permessage-deflate compression");
 
         ap.refer(&mut options.print_headers)
             .add_option(&["-I", "--head"], StoreTrue,
@@ -105,10 +160,97 @@ fn main() {
             .add_option(&["--ping-msg"], Store,
                         "specify a message to to send when pinging the server");
 
+        ap.refer(&mut options.ping_text)
+            .add_option(&["--ping-text"], StoreTrue,
+                        "send pings as text frames instead of ping frames");
+
+        ap.refer(&mut options.ping_timeout)
+            .metavar("SECONDS")
+            .add_option(&["--ping-timeout"], Store,
+                        "disconnect if no pong is received within SECONDS");
+
+        ap.refer(&mut options.print_pongs)
+            .add_option(&["--print-pongs"], StoreTrue,
+                        "print received pongs and their round trip time");
+
+        ap.refer(&mut options.close_code)
+            .metavar("CODE")
+            .add_option(&["--close-code"], Store,
+                        "close code to send when closing the connection");
+
+        ap.refer(&mut options.close_reason)
+            .metavar("REASON")
+            .add_option(&["--close-reason"], Store,
+                        "reason to send when closing the connection");
+
+        ap.refer(&mut options.close_timeout)
+            .metavar("SECONDS")
+            .add_option(&["--close-timeout"], Store,
+                        "how long to wait for the server to confirm a close");
+
+        ap.refer(&mut options.linger)
+            .metavar("SECONDS")
+            .add_option(&["--linger"], Store,
+                        "wait for responses for SECONDS after stdin ends");
+
+        ap.refer(&mut options.count)
+            .metavar("N")
+            .add_option(&["--count"], Store,
+                        "exit after receiving N messages");
+
+        ap.refer(&mut options.until)
+            .metavar("REGEX")
+            .add_option(&["--until"], Store,
+                        "exit once a received message matches REGEX");
+
+        ap.refer(&mut options.timeout)
+            .metavar("SECONDS")
+            .add_option(&["--timeout"], Store,
+                        "fail if still running after SECONDS");
+
         ap.refer(&mut options.login_url)
             .add_option(&["-l", "--login"], Store,
                         "URL to authenticate with before connecting to WS");
 
+        ap.refer(&mut options.login_method)
+            .metavar("METHOD")
+            .add_option(&["--login-method"], Store,
+                        "HTTP method of the login request, POST with data, otherwise GET");
+
+        ap.refer(&mut options.login_data)
+            .metavar("DATA")
+            .add_option(&["--login-data"], Store,
+                        "send DATA as the body of the login request");
+
+        ap.refer(&mut options.login_data_file)
+            .metavar("FILE")
+            .add_option(&["--login-data-file"], Store,
+                        "send the contents of FILE as the body of the login request");
+
+        ap.refer(&mut options.login_headers)
+            .metavar("HEADER")
+            .add_option(&["--login-header"], Collect,
+                        "add a header to the login request only");
+
+        ap.refer(&mut options.login_send_headers)
+            .add_option(&["--login-send-headers"], StoreTrue,
+                        "add the -H headers to the login request as well");
+
+        ap.refer(&mut options.login_token)
+            .metavar("PATH")
+            .add_option(&["--login-token"], Store,
+                        "send the token at PATH in the JSON login response as a Bearer token");
+
+        ap.refer(&mut options.login_token_param)
+            .metavar("NAME")
+            .add_option(&["--login-token-param"], Store,
+                        "send the --login-token or OAuth2 token as query parameter NAME instead");
+
+        ap.refer(&mut options.cookie_jar)
+            .metavar("FILE")
+            .add_option(&["--cookie-jar"], Store,
+                        "load and save cookies in FILE, logging in only without a session cookie");
+
         ap.refer(&mut options.binary_mode)
             .add_option(&["-b", "--binary"], StoreTrue,
                         "enable binary mode");
@@ -117,10 +259,107 @@ fn main() {
             .add_option(&["--follow-redirect"], StoreTrue,
                         "honour HTTP redirection when authenticating");
 
+        ap.refer(&mut options.proxy)
+            .metavar("URL")
+            .add_option(&["--proxy"], Store,
+                        "connect through an HTTP or SOCKS5 proxy");
+
+        ap.refer(&mut options.connect_timeout)
+            .metavar("SECONDS")
+            .add_option(&["--connect-timeout"], Store,
+                        "give up connecting to the server after SECONDS");
+
+        ap.refer(&mut options.handshake_timeout)
+            .metavar("SECONDS")
+            .add_option(&["--handshake-timeout"], Store,
+                        "give up on the TLS and WebSocket handshakes after SECONDS");
+
+        ap.refer(&mut options.idle_timeout)
+            .metavar("SECONDS")
+            .add_option(&["--idle-timeout"], Store,
+                        "disconnect if nothing is received for SECONDS");
+
+        ap.refer(&mut options.cacert)
+            .metavar("FILE")
+            .add_option(&["--cacert"], Store,
+                        "verify the server with the CA certificates in FILE");
+
+        ap.refer(&mut options.cert)
+            .metavar("FILE")
+            .add_option(&["--cert"], Store,
+                        "present a client certificate, as PEM or PKCS#12");
+
+        ap.refer(&mut options.key)
+            .metavar("FILE")
+            .add_option(&["--key"], Store,
+                        "the PEM private key of --cert");
+
+        ap.refer(&mut options.cert_password)
+            .metavar("PASSWORD")
+            .add_option(&["--cert-password"], Store,
+                        "the password of a PKCS#12 --cert");
+
+        ap.refer(&mut options.insecure)
+            .add_option(&["-k", "--insecure"], StoreTrue,
+                        "do not verify the certificate of the server");
+
+        ap.refer(&mut options.pins)
+            .metavar("PIN")
+            .add_option(&["--pin"], Collect,
+                        "require a certificate matching sha256//BASE64 or a fingerprint");
+
         ap.refer(&mut options.echo)
             .add_option(&["-e", "--echo"], StoreTrue,
                         "echo outgoing frames");
 
+        ap.refer(&mut options.output_format)
+            .metavar("FORMAT")
+            .add_option(&["--output-format"], Store,
+                        "print frames as raw payloads (raw) or JSON Lines (jsonl)");
+
+        ap.refer(&mut options.reconnect)
+            .add_option(&["--reconnect"], StoreTrue,
+                        "reconnect to the server if the connection is lost");
+
+        ap.refer(&mut options.reconnect_attempts)
+            .metavar("N")
+            .add_option(&["--reconnect-attempts"], Store,
+                        "give up after N reconnection attempts, 0 is forever");
+
+        ap.refer(&mut options.reconnect_delay)
+            .metavar("SECONDS")
+            .add_option(&["--reconnect-delay"], Store,
+                        "initial delay between reconnection attempts");
+
+        ap.refer(&mut options.reconnect_max_delay)
+            .metavar("SECONDS")
+            .add_option(&["--reconnect-max-delay"], Store,
+                        "maximum delay between reconnection attempts");
+
+        ap.refer(&mut options.reconnect_resend)
+            .add_option(&["--reconnect-resend"], StoreTrue,
+                        "send MESSAGES again after reconnecting");
+
+        ap.refer(&mut options.record)
+            .metavar("FILE")
+            .add_option(&["--record"], Store,
+                        "record all frames with their timing to FILE");
+
+        ap.refer(&mut options.replay)
+            .metavar("FILE")
+            .add_option(&["--replay"], Store,
+                        "send the outgoing frames recorded in FILE instead of reading stdin");
+
+        ap.refer(&mut options.replay_speed)
+            .metavar("FACTOR")
+            .add_option(&["--replay-speed"], Store,
+                        "speed up replay by FACTOR, 2 is twice as fast");
+
+        ap.refer(&mut options.script)
+            .metavar("FILE")
+            .add_option(&["--script"], Store,
+                        "run the steps in FILE against the server and report the results");
+
         // This is a dummy entry used in --help - the actual profile is read
         // before ArgumentParser is invoked
         ap.refer(&mut dummy)
@@ -150,23 +389,71 @@ fn main() {
     }
 
     // Check if url is empty manually, as the user may enter
-    // it either as an argument or via a configuration file
-    if options.url.is_empty() {
+    // it either as an argument or via a configuration file.
+    // A url is not needed when listening for clients, unless relaying.
+    if options.url.is_empty() && (options.listen.is_empty() || options.relay) {
         stderr!("{}: You need to enter a URL", env!("CARGO_PKG_NAME"));
 
         let help_text = from_utf8(&help_text[..])
             .expect("Could not read help text from buffer! File a bug!")
             .to_string();
         stderr!("{}", help_text);
-        exit(1);
+        exit(EXIT_INVALID_ARGUMENT);
+    }
+
+    if !options.login_data.is_empty() && !options.login_data_file.is_empty() {
+        stderr!("{}: Use either --login-data or --login-data-file, not both",
+                env!("CARGO_PKG_NAME"));
+        exit(EXIT_INVALID_ARGUMENT);
     }
 
+    if !options.login_token.is_empty() {
+        if let Err(err) = JsonPath::parse(&options.login_token) {
+            stderr!("{}: --login-token: {}", env!("CARGO_PKG_NAME"), err);
+            exit(EXIT_INVALID_ARGUMENT);
+        }
+    }
+
+    if !options.login_token_param.is_empty() && options.login_token.is_empty() &&
+       options.oauth_token_url.is_empty() {
+        stderr!("{}: --login-token-param needs --login-token or oauth_token_url",
+                env!("CARGO_PKG_NAME"));
+        exit(EXIT_INVALID_ARGUMENT);
+    }
+
+    if !options.oauth_token_url.is_empty() {
+        if !options.login_token.is_empty() {
+            stderr!("{}: Use either --login-token or oauth_token_url, not both",
+                    env!("CARGO_PKG_NAME"));
+            exit(EXIT_INVALID_ARGUMENT);
+        }
+
+        if options.oauth_client_id.is_empty() {
+            stderr!("CONFIG ERROR: oauth_token_url needs oauth_client_id");
+            exit(EXIT_INVALID_ARGUMENT);
+        }
+    }
 
     // Set log level, no logging before this is possible
     log::set_log_level(options.verbosity);
     log!(3, parsed_profile_log);
     log!(3, parsed_conf_log);
+
+    // Cache OAuth2 tokens next to the profile, unless told otherwise
+    if !options.oauth_token_url.is_empty() && options.oauth_token_cache.is_empty() {
+        if let Some(path) = conf::get_data_path(profile, oauth::CACHE_FILE) {
+            options.oauth_token_cache = path.to_string_lossy().into_owned();
+        }
+    }
     log!(3, "Resulting options: {:?}", options);
 
-    program::run_wsta(&mut options);
+    if options.listen.is_empty() && !options.script.is_empty() {
+        script::run_script(&options);
+    } else if options.listen.is_empty() {
+        program::run_wsta(&mut options);
+    } else if options.relay {
+        relay::run_relay(&options);
+    } else {
+        server::run_server(&options);
+    }
 }
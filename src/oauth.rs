@@ -0,0 +1,288 @@
+//! OAuth2 access tokens (RFC 6749) for the upgrade request, fetched from the
+//! `oauth_token_url` of a profile.
+//!
+//! A token is requested with the refresh token grant when there is a
+//! refresh token, and with the client credentials grant otherwise. Tokens
+//! are cached in a file in the XDG data directory of the profile, so that
+//! later runs reuse them until they are about to expire.
+use std::io;
+use std::io::{Read, Write, ErrorKind};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use hyper::Url;
+use hyper::header::{Headers, Authorization, Basic, ContentType, Accept, qitem};
+use hyper::method::Method;
+use hyper::mime::{Mime, TopLevel, SubLevel};
+
+#[cfg(unix)] use std::os::unix::fs::OpenOptionsExt;
+
+use cookies::CookieJar;
+use error::Error;
+use http::{send_login_request, percent_encode};
+use json_path::JsonPath;
+use options::Options;
+use token::Token;
+
+/// The name of the token cache in the data directory of the profile
+pub const CACHE_FILE: &'static str = "oauth_token.json";
+
+/// Get an access token from `options.oauth_token_url`, unless `current` or
+/// the cached token is still valid. New tokens are saved to the cache.
+pub fn access_token(options: &Options,
+                    current: Option<Token>,
+                    jar: &mut CookieJar) -> Result<Token, Error> {
+
+    let cached = match current {
+        Some(token) => Some(token),
+        None => load_cache(options)?
+    };
+
+    if let Some(ref token) = cached {
+        if !token.needs_refresh() {
+            log!(1, "Reusing the OAuth2 token that expires at {:?}",
+                 token.expires_at);
+            return Ok(token.clone());
+        }
+    }
+
+    let refresh_token = cached.and_then(|token| token.refresh_token)
+        .or_else(|| if options.oauth_refresh_token.is_empty() {
+            None
+        } else {
+            Some(options.oauth_refresh_token.clone())
+        });
+
+    let token = match refresh_token {
+        Some(refresh_token) => {
+            let grant = vec![("grant_type", "refresh_token"),
+                             ("refresh_token", &refresh_token[..])];
+
+            match request_token(options, &grant, jar) {
+                Ok(mut token) => {
+                    // The old refresh token stays valid unless a new one
+                    // is issued (RFC 6749, section 6)
+                    token.refresh_token = token.refresh_token.or(Some(refresh_token));
+                    token
+                },
+                Err(err) if !options.oauth_client_secret.is_empty() => {
+                    log!(1, "Refreshing the OAuth2 token failed, using the \
+                             client credentials instead: {}", err);
+                    request_token(options, &[("grant_type", "client_credentials")], jar)?
+                },
+                Err(err) => return Err(err)
+            }
+        },
+        None => request_token(options, &[("grant_type", "client_credentials")], jar)?
+    };
+
+    save_cache(options, &token)?;
+    Ok(token)
+}
+
+/// Send a token request with the parameters of `grant`, authenticating
+/// with the client secret if there is one
+fn request_token(options: &Options,
+                 grant: &[(&str, &str)],
+                 jar: &mut CookieJar) -> Result<Token, Error> {
+
+    let url = Url::parse(&options.oauth_token_url)
+        .map_err(|err| Error::InvalidUrl(options.oauth_token_url.clone(), err))?;
+
+    let body = token_request_body(options, grant);
+    log!(3, "Token request body: {}", body);
+
+    let mut headers = Headers::new();
+    headers.set(ContentType::form_url_encoded());
+    headers.set(Accept(vec![qitem(Mime(TopLevel::Application, SubLevel::Json, vec![]))]));
+
+    if !options.oauth_client_secret.is_empty() {
+        headers.set(Authorization(Basic {
+            username: options.oauth_client_id.clone(),
+            password: Some(options.oauth_client_secret.clone())
+        }));
+    }
+
+    let mut response = send_login_request(options, "OAuth2 token", Method::Post,
+                                          &url, headers, Some(body.into_bytes()),
+                                          jar)?;
+
+    let mut text = String::new();
+    response.read_to_string(&mut text)
+        .map_err(|err| Error::OAuth(format!("{}", err)))?;
+    log!(4, "Response body: {}", text);
+
+    if !response.status.is_success() {
+        return Err(Error::OAuth(format!("{} answered {}{}", url, response.status,
+                                        describe_error_response(&text))));
+    }
+
+    let path = JsonPath::parse(".access_token").unwrap();
+    Token::from_response(&text, &path)
+        .ok_or_else(|| Error::OAuth(String::from("the response has no access_token")))
+}
+
+/// The form encoded body of a token request. The client id is only sent in
+/// the body by public clients, which have no secret to authenticate with.
+fn token_request_body(options: &Options, grant: &[(&str, &str)]) -> String {
+    let mut params = grant.to_vec();
+
+    if !options.oauth_scope.is_empty() {
+        params.push(("scope", &options.oauth_scope));
+    }
+
+    if options.oauth_client_secret.is_empty() && !options.oauth_client_id.is_empty() {
+        params.push(("client_id", &options.oauth_client_id));
+    }
+
+    params.iter()
+        .map(|&(name, value)| format!("{}={}", name, percent_encode(value)))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+/// The error and description of an error response, such as
+/// `{"error": "invalid_client"}`, if it has them
+fn describe_error_response(body: &str) -> String {
+    let json = match Json::from_str(body) {
+        Ok(json) => json,
+        Err(_) => return String::new()
+    };
+
+    let field = |name| json.find(name).and_then(Json::as_string);
+
+    match (field("error"), field("error_description")) {
+        (Some(error), Some(description)) => format!(": {} ({})", error, description),
+        (Some(error), None) => format!(": {}", error),
+        _ => String::new()
+    }
+}
+
+/// The cached token, if there is one for the same token endpoint, client
+/// and scope as in `options`. A cache that cannot be parsed is ignored.
+fn load_cache(options: &Options) -> Result<Option<Token>, Error> {
+    if options.oauth_token_cache.is_empty() {
+        return Ok(None);
+    }
+
+    let mut text = String::new();
+    let result = File::open(&options.oauth_token_cache)
+        .and_then(|mut file| file.read_to_string(&mut text));
+
+    match result {
+        Ok(_) => {},
+        Err(ref err) if err.kind() == ErrorKind::NotFound => {
+            log!(2, "Token cache {} does not exist yet", options.oauth_token_cache);
+            return Ok(None);
+        },
+        Err(err) => return Err(Error::TokenCache(options.oauth_token_cache.clone(), err))
+    }
+
+    let token = parse_cache(&text, options);
+    if token.is_none() {
+        log!(1, "Ignoring the token cache {}, which is for other settings",
+             options.oauth_token_cache);
+    }
+
+    Ok(token)
+}
+
+fn parse_cache(text: &str, options: &Options) -> Option<Token> {
+    let json = Json::from_str(text).ok()?;
+    let field = |name| json.find(name).and_then(Json::as_string);
+
+    if field("token_url") != Some(&options.oauth_token_url) ||
+       field("client_id") != Some(&options.oauth_client_id) ||
+       field("scope") != Some(&options.oauth_scope) {
+        return None;
+    }
+
+    Some(Token {
+        value: String::from(field("access_token")?),
+        expires_at: json.find("expires_at").and_then(Json::as_u64),
+        refresh_token: field("refresh_token").map(String::from)
+    })
+}
+
+fn to_cache(token: &Token, options: &Options) -> String {
+    let mut object = BTreeMap::new();
+    object.insert(String::from("token_url"), options.oauth_token_url.to_json());
+    object.insert(String::from("client_id"), options.oauth_client_id.to_json());
+    object.insert(String::from("scope"), options.oauth_scope.to_json());
+    object.insert(String::from("access_token"), token.value.to_json());
+    object.insert(String::from("expires_at"), token.expires_at.to_json());
+    object.insert(String::from("refresh_token"), token.refresh_token.to_json());
+
+    Json::Object(object).to_string()
+}
+
+/// Save `token` to `options.oauth_token_cache`, if it is set. The cache
+/// holds secrets, so only the user may read it.
+fn save_cache(options: &Options, token: &Token) -> Result<(), Error> {
+    if options.oauth_token_cache.is_empty() {
+        return Ok(());
+    }
+
+    log!(3, "Saving the OAuth2 token to {}", options.oauth_token_cache);
+    write_private(Path::new(&options.oauth_token_cache), &to_cache(token, options))
+        .map_err(|err| Error::TokenCache(options.oauth_token_cache.clone(), err))
+}
+
+fn write_private(path: &Path, text: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut open_options = OpenOptions::new();
+    open_options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    open_options.mode(0o600);
+
+    open_options.open(path)?.write_all(text.as_bytes())
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn tokens_survive_the_cache() {
+    let mut options = Options::new();
+    options.oauth_token_url = String::from("https://auth.example.com/token");
+    options.oauth_client_id = String::from("wsta");
+
+    let token = Token {
+        value: String::from("abc"),
+        expires_at: Some(4102444800),
+        refresh_token: Some(String::from("def"))
+    };
+    let cache = to_cache(&token, &options);
+    assert_eq!(parse_cache(&cache, &options), Some(token));
+
+    // A token for another scope is not reused
+    options.oauth_scope = String::from("admin");
+    assert_eq!(parse_cache(&cache, &options), None);
+    assert_eq!(parse_cache("not json", &options), None);
+}
+
+#[test]
+fn token_requests_are_form_encoded() {
+    let mut options = Options::new();
+    options.oauth_client_id = String::from("wsta");
+    options.oauth_scope = String::from("read write");
+
+    let grant = [("grant_type", "client_credentials")];
+    assert_eq!(token_request_body(&options, &grant),
+               "grant_type=client_credentials&scope=read%20write&client_id=wsta");
+
+    options.oauth_client_secret = String::from("secret");
+    assert_eq!(token_request_body(&options, &grant),
+               "grant_type=client_credentials&scope=read%20write");
+
+    assert_eq!(describe_error_response(r#"{"error": "invalid_client"}"#),
+               ": invalid_client");
+    assert_eq!(describe_error_response("<html>"), "");
+}
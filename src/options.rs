@@ -1,10 +1,37 @@
 //! The command line options provided to the program
 use std::vec::Vec;
+use std::str::FromStr;
 
 use config::types::Config;
-use conf::{get_str,get_str_or,get_bool,get_vec};
+use conf::{get_str,get_str_or,get_bool,get_vec,get_u64_or,get_f64_or,get_parsed_or,
+           get_login_steps};
+use error::Error;
+use http::LoginStep;
 
-#[derive(Debug)]
+/// How frames are printed to stdout
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputFormat {
+    /// The payload of the frame, with outgoing frames prefixed by "> "
+    Raw,
+
+    /// One JSON object per frame, including its metadata
+    JsonLines
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<OutputFormat, String> {
+        match format {
+            "raw" => Ok(OutputFormat::Raw),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            _ => Err(format!("Unknown output format '{}'. Use raw or jsonl",
+                             format))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Options {
 
     /// The verbosity level of the application. Should be a number
@@ -19,24 +46,133 @@ pub struct Options {
     /// The WebSocket URL to connect to.
     pub url: String,
 
-    /// Optional: A GET URL to authenticate with before connecting
+    /// Optional: An address to listen for WebSocket clients on. When
+    /// provided, wsta acts as a server instead of connecting to `url`.
+    pub listen: String,
+
+    /// When listening, connect every client to `url` and print the frames
+    /// going in both directions, instead of bridging clients to stdin/stdout.
+    pub relay: bool,
+
+    /// Optional: A URL to authenticate with before connecting
     /// to the main url.
     pub login_url: String,
 
+    /// The HTTP method of the login request. Defaults to POST when there is
+    /// login data, and GET otherwise.
+    pub login_method: String,
+
+    /// Optional: The body of the login request.
+    pub login_data: String,
+
+    /// Optional: A file to read the body of the login request from.
+    pub login_data_file: String,
+
+    /// Headers to add to the login request only.
+    pub login_headers: Vec<String>,
+
+    /// Add `headers` to the login request as well as the upgrade request.
+    pub login_send_headers: bool,
+
+    /// Optional: The JSON path of a token in the login response, such as
+    /// `.access_token`, to send in an `Authorization: Bearer` header.
+    pub login_token: String,
+
+    /// Optional: Send the `login_token` or OAuth2 token as this query
+    /// parameter of `url` instead of in a header.
+    pub login_token_param: String,
+
+    /// Optional: The OAuth2 token endpoint to get an access token from
+    /// before connecting. Only set from the config, as are the other
+    /// `oauth_` options.
+    pub oauth_token_url: String,
+
+    pub oauth_client_id: String,
+
+    /// The client secret. Without one, the client id is sent in the body of
+    /// token requests instead of authenticating with it.
+    pub oauth_client_secret: String,
+
+    /// Optional: The scope to request, separated by spaces.
+    pub oauth_scope: String,
+
+    /// Optional: A refresh token to get the first access token with, instead
+    /// of the client credentials.
+    pub oauth_refresh_token: String,
+
+    /// The file access tokens are cached in. Defaults to a file in the XDG
+    /// data directory of the profile.
+    pub oauth_token_cache: String,
+
+    /// Requests to send in order before connecting, which may extract values
+    /// for later steps, the url and the headers. Only set from the config.
+    pub login_steps: Vec<LoginStep>,
+
+    /// Optional: A file to load cookies from and save them to, in the
+    /// Netscape format. Login is skipped while it holds a cookie for `url`.
+    pub cookie_jar: String,
+
     /// When passed, this flag will cause the program to follow
     /// HTTP GET redirection encountered when calling login_url.
     pub follow_redirect: bool,
 
+    /// Optional: The proxy to connect through, for both the WebSocket and
+    /// the login request. Overrides the proxy environment variables.
+    pub proxy: String,
+
+    /// Number of seconds to wait for the connection to the server, or to
+    /// the proxy, to be established. 0 means use the OS default.
+    pub connect_timeout: u64,
+
+    /// Number of seconds to wait for the TLS and WebSocket handshakes to
+    /// complete once connected. 0 means wait forever.
+    pub handshake_timeout: u64,
+
+    /// Number of seconds the server may go without sending anything before
+    /// the connection is considered lost. 0 means wait forever.
+    pub idle_timeout: u64,
+
+    /// Optional: A PEM file of CA certificates to verify the server with,
+    /// instead of the system's CA certificates.
+    pub cacert: String,
+
+    /// Optional: A client certificate to present to the server, as PEM or
+    /// as a PKCS#12 bundle ending in .p12 or .pfx.
+    pub cert: String,
+
+    /// Optional: The PEM private key of `cert`, if it is not in `cert`.
+    pub key: String,
+
+    /// The password of a PKCS#12 `cert`.
+    pub cert_password: String,
+
+    /// When true, the certificate of the server is not verified.
+    pub insecure: bool,
+
+    /// Pins as `sha256//BASE64` public key hashes or certificate
+    /// fingerprints. A certificate of the server must match one of them.
+    pub pins: Vec<String>,
+
     /// Echo outgoing frames, as well as the incoming frames. Outgoing
     /// frames will be prefixed with ">".
     pub echo: bool,
 
+    /// How frames are printed to stdout, see `OutputFormat`.
+    pub output_format: OutputFormat,
+
     /// Print the headers of any HTTP request when true.
     pub print_headers: bool,
 
     /// Headers
     pub headers: Vec<String>,
 
+    /// The subprotocols to offer in the upgrade request, in order of
+    /// preference. The server must select one of them.
+    pub subprotocols: Vec<String>,
+
+    /// When true, permessage-deflate is not offered to the server.
+    pub no_compression: bool,
+
     /// Messages to send after connecting to the server
     pub messages: Vec<String>,
 
@@ -47,13 +183,82 @@ pub struct Options {
     /// The string to send to the server when sending a ping frame.
     pub ping_msg: String,
 
+    /// When true, pings are sent as text frames containing `ping_msg`
+    /// rather than as ping control frames, and no pongs are expected.
+    pub ping_text: bool,
+
+    /// Number of seconds to wait for a pong before the connection is
+    /// considered lost. 0 means wait forever.
+    pub ping_timeout: u64,
+
+    /// When true, received pongs are printed along with the round trip time
+    pub print_pongs: bool,
+
+    /// The close code to send when closing the connection
+    pub close_code: u16,
+
+    /// The reason to send along with the close code
+    pub close_reason: String,
+
+    /// Number of seconds to wait for the server to answer a close frame
+    pub close_timeout: u64,
+
+    /// Number of seconds to keep the connection open after stdin has reached
+    /// EOF, to wait for responses
+    pub linger: u64,
+
+    /// Number of text or binary frames to receive before closing the
+    /// connection and exiting successfully. 0 means no limit.
+    pub count: u64,
+
+    /// Optional: A regular expression. The connection is closed, and wsta
+    /// exits successfully, once an incoming frame matches it.
+    pub until: String,
+
+    /// Number of seconds after which the run fails if it is still going.
+    /// 0 means no limit.
+    pub timeout: u64,
+
     /// If provided, will turn the program into a binary mode, reading 255 bytes
     /// at a time and sending frames when the buffer is filled
     pub binary_mode: bool,
 
     /// Specifies the amount of bytes per frame to send when
     /// sending binary data.
-    pub binary_frame_size: String
+    pub binary_frame_size: String,
+
+    /// When true, wsta will attempt to reconnect to the server if the
+    /// connection is lost, instead of exiting.
+    pub reconnect: bool,
+
+    /// The maximum number of reconnection attempts to make before giving
+    /// up. 0 means retry forever.
+    pub reconnect_attempts: u64,
+
+    /// The number of seconds to wait before the first reconnection attempt.
+    /// The delay is doubled for each failed attempt.
+    pub reconnect_delay: u64,
+
+    /// The upper bound of the reconnection delay, in seconds.
+    pub reconnect_max_delay: u64,
+
+    /// Send `messages` again after every successful reconnection.
+    pub reconnect_resend: bool,
+
+    /// Optional: A file to record every incoming and outgoing frame to.
+    pub record: String,
+
+    /// Optional: A recording to send the outgoing frames of, instead of
+    /// reading stdin.
+    pub replay: String,
+
+    /// The factor to speed up the replay by. 2.0 sends frames twice as fast
+    /// as they were recorded.
+    pub replay_speed: f64,
+
+    /// Optional: A file with steps to run against the connection, instead
+    /// of reading stdin.
+    pub script: String
 }
 
 impl Options {
@@ -61,37 +266,137 @@ impl Options {
     pub fn new() -> Options {
         Options {
             url: String::new(),
+            listen: String::new(),
+            relay: false,
             login_url: String::new(),
+            login_method: String::new(),
+            login_data: String::new(),
+            login_data_file: String::new(),
+            login_headers: Vec::new(),
+            login_send_headers: false,
+            login_token: String::new(),
+            login_token_param: String::new(),
+            oauth_token_url: String::new(),
+            oauth_client_id: String::new(),
+            oauth_client_secret: String::new(),
+            oauth_scope: String::new(),
+            oauth_refresh_token: String::new(),
+            oauth_token_cache: String::new(),
+            login_steps: Vec::new(),
+            cookie_jar: String::new(),
             follow_redirect: false,
+            proxy: String::new(),
+            connect_timeout: 0,
+            handshake_timeout: 0,
+            idle_timeout: 0,
+            cacert: String::new(),
+            cert: String::new(),
+            key: String::new(),
+            cert_password: String::new(),
+            insecure: false,
+            pins: Vec::new(),
             echo: false,
+            output_format: OutputFormat::Raw,
             verbosity: 0,
             print_headers: false,
             headers: Vec::new(),
+            subprotocols: Vec::new(),
+            no_compression: false,
             messages: Vec::new(),
             ping_interval: None,
             ping_msg: String::from("ping"),
+            ping_text: false,
+            ping_timeout: 0,
+            print_pongs: false,
+            close_code: 1000,
+            close_reason: String::new(),
+            close_timeout: 5,
+            linger: 0,
+            count: 0,
+            until: String::new(),
+            timeout: 0,
             binary_mode: false,
-            binary_frame_size: String::from("256")
+            binary_frame_size: String::from("256"),
+            reconnect: false,
+            reconnect_attempts: 10,
+            reconnect_delay: 1,
+            reconnect_max_delay: 60,
+            reconnect_resend: false,
+            record: String::new(),
+            replay: String::new(),
+            replay_speed: 1.0,
+            script: String::new()
         }
     }
 
     /// Build a new Options object with defaults taken from
-    /// the config file
-    pub fn build_from_config(config: &Config) -> Options {
-        Options {
+    /// the config file. Fails if a value in it is invalid.
+    pub fn build_from_config(config: &Config) -> Result<Options, Error> {
+        Ok(Options {
             url: get_str(config, "url"),
+            listen: get_str(config, "listen"),
+            relay: get_bool(config, "relay"),
             login_url: get_str(config, "login_url"),
+            login_method: get_str(config, "login_method"),
+            login_data: get_str(config, "login_data"),
+            login_data_file: get_str(config, "login_data_file"),
+            login_headers: get_vec(config, "login_headers"),
+            login_send_headers: get_bool(config, "login_send_headers"),
+            login_token: get_str(config, "login_token"),
+            login_token_param: get_str(config, "login_token_param"),
+            oauth_token_url: get_str(config, "oauth_token_url"),
+            oauth_client_id: get_str(config, "oauth_client_id"),
+            oauth_client_secret: get_str(config, "oauth_client_secret"),
+            oauth_scope: get_str(config, "oauth_scope"),
+            oauth_refresh_token: get_str(config, "oauth_refresh_token"),
+            oauth_token_cache: get_str(config, "oauth_token_cache"),
+            login_steps: get_login_steps(config, "login_steps")?,
+            cookie_jar: get_str(config, "cookie_jar"),
             follow_redirect: get_bool(config, "follow_redirect"),
+            proxy: get_str(config, "proxy"),
+            connect_timeout: get_u64_or(config, "connect_timeout", 0)?,
+            handshake_timeout: get_u64_or(config, "handshake_timeout", 0)?,
+            idle_timeout: get_u64_or(config, "idle_timeout", 0)?,
+            cacert: get_str(config, "cacert"),
+            cert: get_str(config, "cert"),
+            key: get_str(config, "key"),
+            cert_password: get_str(config, "cert_password"),
+            insecure: get_bool(config, "insecure"),
+            pins: get_vec(config, "pins"),
             echo: get_bool(config, "echo"),
+            output_format: get_parsed_or(config, "output_format",
+                                         OutputFormat::Raw)?,
             verbosity: 0,
             print_headers: get_bool(config, "print_headers"),
             headers: get_vec(config, "headers"),
+            subprotocols: get_vec(config, "subprotocols"),
+            no_compression: get_bool(config, "no_compression"),
             messages: get_vec(config, "messages"),
             ping_interval: None,
             ping_msg: get_str_or(config, "ping_msg", "ping"),
+            ping_text: get_bool(config, "ping_text"),
+            ping_timeout: get_u64_or(config, "ping_timeout", 0)?,
+            print_pongs: get_bool(config, "print_pongs"),
+            close_code: get_u64_or(config, "close_code", 1000)?
+                .min(u16::MAX as u64) as u16,
+            close_reason: get_str(config, "close_reason"),
+            close_timeout: get_u64_or(config, "close_timeout", 5)?,
+            linger: get_u64_or(config, "linger", 0)?,
+            count: get_u64_or(config, "count", 0)?,
+            until: get_str(config, "until"),
+            timeout: get_u64_or(config, "timeout", 0)?,
             binary_mode: get_bool(config, "binary_mode"),
             // TODO Make int
             binary_frame_size: get_str_or(config, "binary_frame_size", "256"),
-        }
+            reconnect: get_bool(config, "reconnect"),
+            reconnect_attempts: get_u64_or(config, "reconnect_attempts", 10)?,
+            reconnect_delay: get_u64_or(config, "reconnect_delay", 1)?,
+            reconnect_max_delay: get_u64_or(config, "reconnect_max_delay", 60)?,
+            reconnect_resend: get_bool(config, "reconnect_resend"),
+            record: get_str(config, "record"),
+            replay: get_str(config, "replay"),
+            replay_speed: get_f64_or(config, "replay_speed", 1.0),
+            script: get_str(config, "script")
+        })
     }
 }
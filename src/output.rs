@@ -0,0 +1,218 @@
+//! Printing of frames to stdout, either as raw payloads or as JSON Lines.
+//!
+//! In the JSON Lines format every frame is printed as one JSON object:
+//!
+//! ```text
+//! {"direction":"in","length":5,"opcode":"text","payload":"hello","timestamp":1482012345678}
+//! ```
+//!
+//! `timestamp` is the number of milliseconds since the UNIX epoch. Text
+//! payloads are printed as-is, all other payloads are base64 encoded. Close
+//! frames additionally carry the close `code`, if any, and pongs printed with
+//! `--print-pongs` carry the round trip time in milliseconds as `rtt`.
+use std::io;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::Json;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+
+use websocket::Message;
+use websocket::message::Type;
+
+use wsta::Error;
+use wsta::options::{Options, OutputFormat};
+
+use ws;
+use record::{Recorder, create_recorder};
+
+/// The direction a frame was travelling in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Direction {
+    /// Sent by wsta
+    Outgoing,
+
+    /// Received by wsta
+    Incoming
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Direction::Outgoing => "out",
+            Direction::Incoming => "in"
+        }
+    }
+}
+
+/// Handles every frame sent or received, printing and recording it as
+/// requested by the options. Cloning an Output is cheap, so every thread
+/// handling frames can have its own.
+#[derive(Clone)]
+pub struct Output {
+    pub echo: bool,
+    pub format: OutputFormat,
+    pub recorder: Option<Recorder>
+}
+
+impl Output {
+    /// Fails if the `--record` file cannot be created
+    pub fn from_options(options: &Options) -> Result<Output, Error> {
+        Ok(Output {
+            echo: options.echo,
+            format: options.output_format,
+            recorder: create_recorder(&options.record)?
+        })
+    }
+
+    /// Handle a frame that has been sent. It is printed if `echo` is true.
+    pub fn outgoing(&self, message: &Message) {
+        self.record(Direction::Outgoing, message);
+
+        if self.echo {
+            self.print(Direction::Outgoing, message.clone());
+        }
+    }
+
+    /// Handle a frame that has been received
+    pub fn incoming(&self, message: Message) {
+        self.record(Direction::Incoming, &message);
+        self.print(Direction::Incoming, message);
+    }
+
+    /// Add a frame to the recording, if any
+    pub fn record(&self, direction: Direction, message: &Message) {
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(direction, message);
+        }
+    }
+
+    /// Print a frame to stdout. In the raw format, outgoing frames are
+    /// prefixed with "> ", only outgoing text frames are printed, and
+    /// incoming control frames are not printed.
+    pub fn print(&self, direction: Direction, message: Message) {
+        match self.format {
+            OutputFormat::Raw => match (direction, message.opcode) {
+                (Direction::Incoming, Type::Text) |
+                (Direction::Incoming, Type::Binary) => {
+                    ws::message_to_stdout(message, "")
+                },
+                (Direction::Outgoing, Type::Text) => {
+                    ws::message_to_stdout(message, "> ")
+                },
+                (_, opcode) => log!(3, "Not printing {} frame",
+                                    opcode_name(opcode))
+            },
+            OutputFormat::JsonLines => {
+                println!("{}", encode_message(now_millis(), direction,
+                                              &message));
+            }
+        }
+
+        io::stdout().flush().unwrap_or(());
+    }
+
+    /// Print a pong that has been received, along with the round trip time
+    /// of the ping it answered, if known
+    pub fn pong(&self, message: Message, rtt: Option<Duration>) {
+        let rtt = rtt.map(|rtt| rtt.as_secs() * 1000 +
+                                (rtt.subsec_nanos() / 1000000) as u64);
+
+        match self.format {
+            OutputFormat::Raw => match rtt {
+                Some(rtt) => println!("pong {} ms", rtt),
+                None => println!("pong")
+            },
+            OutputFormat::JsonLines => {
+                let mut object = message_to_json(now_millis(),
+                                                 Direction::Incoming,
+                                                 &message);
+                if let Some(rtt) = rtt {
+                    object.insert(String::from("rtt"), Json::U64(rtt));
+                }
+
+                println!("{}", Json::Object(object));
+            }
+        }
+
+        io::stdout().flush().unwrap_or(());
+    }
+}
+
+/// The number of milliseconds since the UNIX epoch
+fn now_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() * 1000 + (now.subsec_nanos() / 1000000) as u64
+}
+
+/// The name of the opcode of a message
+pub fn opcode_name(opcode: Type) -> &'static str {
+    match opcode {
+        Type::Text => "text",
+        Type::Binary => "binary",
+        Type::Ping => "ping",
+        Type::Pong => "pong",
+        Type::Close => "close"
+    }
+}
+
+/// Serialize a message and its metadata to a single line of JSON
+fn encode_message(timestamp: u64, direction: Direction,
+                  message: &Message) -> String {
+    Json::Object(message_to_json(timestamp, direction, message)).to_string()
+}
+
+/// Build the JSON object describing a message and its metadata
+fn message_to_json(timestamp: u64, direction: Direction,
+                   message: &Message) -> BTreeMap<String, Json> {
+
+    let mut object = BTreeMap::new();
+
+    object.insert(String::from("timestamp"), Json::U64(timestamp));
+    object.insert(String::from("direction"),
+                  Json::String(String::from(direction.as_str())));
+    object.insert(String::from("opcode"),
+                  Json::String(String::from(opcode_name(message.opcode))));
+    object.insert(String::from("length"),
+                  Json::U64(message.payload.len() as u64));
+
+    let payload = match message.opcode {
+        Type::Text => String::from_utf8_lossy(&message.payload).into_owned(),
+        _ => message.payload.to_base64(STANDARD)
+    };
+    object.insert(String::from("payload"), Json::String(payload));
+
+    if let Some(code) = message.cd_status_code {
+        object.insert(String::from("code"), Json::U64(code as u64));
+    }
+
+    object
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn output_format_is_parsed() {
+    assert_eq!("raw".parse::<OutputFormat>(), Ok(OutputFormat::Raw));
+    assert_eq!("jsonl".parse::<OutputFormat>(), Ok(OutputFormat::JsonLines));
+    assert!("xml".parse::<OutputFormat>().is_err());
+}
+
+#[test]
+fn messages_are_encoded_as_json() {
+    let text = Message::text("hello \"world\"");
+    assert_eq!(encode_message(1000, Direction::Incoming, &text),
+               concat!(r#"{"direction":"in","length":13,"opcode":"text","#,
+                       r#""payload":"hello \"world\"","timestamp":1000}"#));
+
+    let binary = Message::binary(vec![0, 1, 2]);
+    assert_eq!(encode_message(1000, Direction::Outgoing, &binary),
+               concat!(r#"{"direction":"out","length":3,"opcode":"binary","#,
+                       r#""payload":"AAEC","timestamp":1000}"#));
+
+    let close = Message::close_because(1000, "bye");
+    assert_eq!(encode_message(1000, Direction::Incoming, &close),
+               concat!(r#"{"code":1000,"direction":"in","length":3,"#,
+                       r#""opcode":"close","payload":"Ynll","timestamp":1000}"#));
+}
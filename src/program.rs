@@ -1,219 +1,576 @@
 use std::io;
 use std::io::Write;
-use std::sync::{Arc, Mutex};
+use std::cmp::{min, max};
+use std::sync::mpsc::{channel, Sender as ChannelSender, Receiver as ChannelReceiver,
+                      RecvTimeoutError};
 use std::thread;
 use std::process::exit;
-use std::time::{SystemTime, Duration};
+use std::time::{Duration, Instant};
 
-use websocket::{Client, Message, Sender};
-use websocket::client::Sender as SenderObj;
-use websocket::client::Receiver as ReceiverObj;
-use websocket::client::request::{Request, Url};
-use websocket::stream::WebSocketStream;
+use regex::Regex;
+
+use websocket::Message;
+use wsta::deflate::Sender as SenderObj;
+use wsta::deflate::Receiver as ReceiverObj;
+use websocket::client::request::Url;
+use websocket::result::WebSocketResult;
+
+use wsta::{Error, Session};
+use wsta::error;
+use wsta::error::{EXIT_CONNECTION_LOST, EXIT_TIMEOUT, close_exit_code};
+use wsta::session;
+use wsta::token::Token;
+use wsta::options::Options;
 
 use ws;
-use options::Options;
+use ws::{Event, Pinger, ReaderEvent};
+use close;
 use frame_data::FrameData;
-use http::{fetch_session_cookie, print_headers};
+use record::spawn_replay;
+use output::Output;
 
-pub fn run_wsta(options: &mut Options) {
+/// The shortest delay between reconnection attempts, in seconds
+const MIN_RECONNECT_DELAY: u64 = 1;
 
-    // Get the URL
-    log!(2, "About to unwrap: {}", options.url);
-    let url = match Url::parse(&options.url) {
-        Ok(res) => res,
-        Err(err) => {
-            log!(1, "Error object: {:?}", err);
-            stderr!("An error occured while parsing '{}' as a WS URL: {}",
-                    options.url, err);
-            exit(1);
-        }
-    };
-    log!(3, "Parsed URL: {:?}", url);
+/// How a single connection ended
+enum SessionEnd {
 
-    let origin = get_origin(&url);
-    log!(3, "Parsed Origin string: {}", origin);
+    /// The connection was lost without a close handshake
+    Lost,
 
-    // Connect to the server
-    log!(2, "About to connect to {}", url);
-    let mut request = match Client::connect(url) {
-        Ok(res) => res,
-        Err(err) => {
-            log!(1, "Error: {:?}", err);
-            stderr!("An error occured while connecting to '{}': {}",
-                           options.url, err);
-            exit(1);
+    /// Sending to the server failed, or the server stopped answering pings.
+    /// The error has already been printed.
+    Failed(Error),
+
+    /// The server closed the connection with the given close code
+    Closed(Option<u16>),
+
+    /// wsta closed the connection, and should exit with the given code
+    Finished(i32)
+}
+
+/// What has been read from the input but not handled yet. This outlives any
+/// single connection, so that nothing typed while reconnecting is lost.
+struct PendingInput {
+
+    /// Frames read while there was no connection to send them to
+    frames: Vec<FrameData>,
+
+    /// When to close the connection. Set once the input has ended.
+    close_at: Option<Instant>
+}
+
+impl PendingInput {
+
+    /// Close the connection `--linger` seconds from now. With `--count` or
+    /// `--until`, the connection is instead kept open until they are met.
+    fn end(&mut self, options: &Options) {
+        if options.count > 0 || !options.until.is_empty() {
+            log!(2, "Input ended, waiting for --count or --until");
+            return;
         }
-    };
 
-    // Set Origin header to be equal to the websocket url
-    request.headers.set_raw("Origin", vec![origin.into_bytes()]);
+        log!(2, "Closing the connection in {} seconds", options.linger);
+        self.close_at = Instant::now()
+            .checked_add(Duration::from_secs(options.linger));
+    }
+}
+
+/// When the run is over, as requested with `--count`, `--until` and
+/// `--timeout`. This also outlives any single connection, so that messages
+/// received before reconnecting count too.
+struct Goal {
 
-    // Authenticate if requested
-    if !options.login_url.is_empty() {
-        let session_cookie = fetch_session_cookie(options);
-        log!(2, "Got session cookie: {:?}", session_cookie);
+    /// The number of messages to receive, if limited
+    count: Option<u64>,
 
-        if session_cookie.is_some() {
-            request.headers.set(session_cookie.unwrap());
-            log!(3, "Session cookie set on request. Headers are now: {:?}",
-                 request.headers);
-        } else {
-            log!(1, "session_cookie object: {:?}", session_cookie);
+    /// The number of messages received so far
+    received: u64,
+
+    /// When to give up, if ever
+    deadline: Option<Instant>
+}
 
-            stderr!(concat!("Attempted to fetch session cookie, but no ",
-              "cookies were found in response's SetCookie header.",
-              "Try looking at -I"));
-            exit(1);
+impl Goal {
+    fn from_options(options: &Options) -> Goal {
+        Goal {
+            count: match options.count {
+                0 => None,
+                count => Some(count)
+            },
+            received: 0,
+            deadline: match options.timeout {
+                0 => None,
+                timeout => Instant::now().checked_add(Duration::from_secs(timeout))
+            }
         }
     }
 
-    // Add the headers passed from command line arguments
-    if !options.headers.is_empty() {
-        add_headers_to_request(&mut request, &mut options.headers);
+    /// Count a received message, which `matched` --until or not
+    ///
+    /// # Returns
+    /// Returns true when the run is over.
+    fn receive(&mut self, matched: bool) -> bool {
+        self.received += 1;
+        matched || self.count.map_or(false, |count| self.received >= count)
     }
 
-    // Print request
-    if options.print_headers {
-        print_headers("WebSocket upgrade request", &request.headers, None);
+    /// Whether `--timeout` has passed
+    fn timed_out(&self) -> bool {
+        self.deadline.map_or(false, |deadline| Instant::now() >= deadline)
     }
+}
 
-    // Send the request
-    log!(3, "About to send and unwrap request");
-    let response = match request.send() {
-        Ok(response) => {
-            log!(3, "Request sent");
+pub fn run_wsta(options: &mut Options) {
 
-            response
-        },
+    close::validate_close_code(options.close_code)
+        .unwrap_or_else(|err| exit_with(err));
+    let until = parse_until(&options.until).unwrap_or_else(|err| exit_with(err));
+    let mut goal = Goal::from_options(options);
+
+    // The --login-token or OAuth2 token is reused when reconnecting, until
+    // it expires
+    let mut token = None;
+
+    let mut client = match connect_before(options, &mut token, &goal) {
+        Ok(client) => client,
         Err(err) => {
-            log!(1, "Error object: {:?}", err);
-            stderr!("An error occured when connecting: {}", err);
-            exit(1);
+            stderr!("{}", describe_error(options, &err));
+            exit(err.exit_code());
         }
     };
 
-    // Dump headers when requested
-    if options.print_headers {
-        print_headers("WebSocket upgrade response",
-                      &response.headers, Some(response.status));
-    }
+    // Frames to send, signals and control frames from the server all arrive
+    // through this channel, so the main loop can block until one of them does
+    let (event_tx, events) = channel();
 
-    // Ensure the response is valid and show an error if not
-    match response.validate() {
-        Err(error) => {
-            log!(1, "Invalid reponse: {:?}", error);
-            stderr!("{}", error);
+    // From now on, SIGINT and SIGTERM close the connection before exiting
+    close::spawn_signal_handler(event_tx.clone())
+        .unwrap_or_else(|err| exit_with(err));
 
-            if !options.print_headers {
-                stderr!("Try using -I for more info");
-            }
+    // The stdin reader outlives any single connection. Recording and replay
+    // timings are relative to the first connection.
+    spawn_input(options, event_tx.clone()).unwrap_or_else(|err| exit_with(err));
+    let output = Output::from_options(options).unwrap_or_else(|err| exit_with(err));
 
-            exit(1);
-        },
-        _ => stderr!("Connected to {}", options.url)
+    let mut input = PendingInput {
+        frames: Vec::new(),
+        close_at: None
+    };
+
+    // Pre-provided messages are always sent on the first connection, and
+    // after reconnecting only if requested
+    let mut resend = true;
+
+    // Every connection gets an id, so that events from the reader thread of
+    // an earlier connection can be told apart
+    let mut session = 0;
+
+    loop {
+        let (mut sender, receiver) = client.split();
+        session += 1;
+
+        // Send pre-provided messages if present. A failure here means the
+        // connection is gone, which the session below will notice
+        if resend && !options.messages.is_empty() {
+            send_messages(&mut sender, &options.messages, &output)
+                .unwrap_or(());
+        }
+
+        ws::spawn_websocket_reader::<ReceiverObj>
+            (receiver, output.clone(), until.clone(), session,
+             event_tx.clone());
+
+        let end = run_session(options, &mut sender, session, &events,
+                              &mut input, &mut goal, &output);
+
+        // Make sure the old reader thread lets go of the socket
+        sender.shutdown_all().unwrap_or(());
+
+        // A normal closure by the server is not something to reconnect from
+        match end {
+            SessionEnd::Finished(code) => exit(code),
+            SessionEnd::Closed(code) if !options.reconnect ||
+                                        code == Some(close::NORMAL_CLOSURE) => {
+                exit(close_exit_code(code));
+            },
+            SessionEnd::Lost if !options.reconnect => exit(EXIT_CONNECTION_LOST),
+            SessionEnd::Failed(ref err) if !options.reconnect => {
+                exit(err.exit_code());
+            },
+            _ => {}
+        }
+
+        client = reconnect(options, &events, &mut input, &goal, &mut token);
+        resend = options.reconnect_resend;
     }
+}
 
-    // Get a Client
-    let client = response.begin();
-    log!(3, "Client created");
+/// Start reading the frames to send, either from stdin or from a recording
+/// when `--replay` is used. The frames are sent to `events`, followed by
+/// `Event::InputEnded` when there is nothing more to send.
+pub fn spawn_input(options: &Options,
+                   events: ChannelSender<Event>) -> Result<(), Error> {
+    if options.replay.is_empty() {
+        ws::spawn_stdin_reader::<ChannelSender<Event>>
+            (options.binary_mode, options.binary_frame_size.clone(), events);
+        Ok(())
+    } else {
+        spawn_replay(&options.replay, options.replay_speed, events)
+    }
+}
 
-    // Send message
-    let (mut sender, receiver) = client.split();
+/// Compile the `--until` regular expression, if any
+fn parse_until(until: &str) -> Result<Option<Regex>, Error> {
+    if until.is_empty() {
+        return Ok(None);
+    }
 
-    // Send pre-provided messages if preesnt
-    if !options.messages.is_empty() {
-        send_messages(&mut sender, &mut options.messages, options.echo);
+    match Regex::new(until) {
+        Ok(regex) => Ok(Some(regex)),
+        Err(err) => {
+            Err(Error::InvalidArgument(format!("Invalid regular expression \
+                                                '{}' for --until: {}",
+                                               until, err)))
+        }
     }
+}
 
-    ws::spawn_websocket_reader::<ReceiverObj<WebSocketStream>>(receiver);
+/// Parse the WebSocket URL, exiting if it is invalid
+pub fn parse_url(raw_url: &str) -> Url {
+    session::parse_url(raw_url).unwrap_or_else(|err| exit_with(err))
+}
+
+/// Print `err` and exit with its exit code
+pub fn exit_with(err: Error) -> ! {
+    stderr!("{}", error::describe(&err));
+    exit(err.exit_code());
+}
 
-    // Share mutable data between writer thread and main thread
-    // using a lockable Mutex.
-    // Mutex will block threads waiting for the lock to become available
-    let stdin_buffer = ws::spawn_stdin_reader::<Arc<Mutex<Vec<FrameData>>>>
-        (options.echo, options.binary_mode, options.binary_frame_size.clone());
+/// Run the main loop for a single connection, sending anything read from
+/// stdin and pinging the server when requested. Blocks until one of those
+/// needs to be done, and returns when the connection is lost or closed by
+/// either side.
+///
+/// The connection is closed with a close handshake when a signal is
+/// received, `--linger` seconds after the input has ended, or when `goal` has
+/// been reached or has timed out. It is given up on when nothing has been
+/// received for `--idle-timeout` seconds.
+fn run_session(options: &Options,
+               sender: &mut SenderObj,
+               session: u64,
+               events: &ChannelReceiver<Event>,
+               input: &mut PendingInput,
+               goal: &mut Goal,
+               output: &Output) -> SessionEnd {
+
+    let mut pinger = Pinger::from_options(options);
+
+    // Any frame from the server resets the idle timer
+    let idle_timeout = match options.idle_timeout {
+        0 => None,
+        timeout => Some(Duration::from_secs(timeout))
+    };
+    let mut last_received = Instant::now();
 
-    // Variables for checking against a ping interval
-    let ping_interval = options.ping_interval.map(|i| Duration::from_secs(i));
-    let mut last_time = SystemTime::now();
+    // Frames read while reconnecting go out first
+    for frame in input.frames.drain(..) {
+        if let Err(err) = ws::send_frame(sender, frame, output) {
+            return SessionEnd::Failed(Error::Send(err));
+        }
+    }
 
     log!(3, "Entering main loop");
     loop {
 
-        // Read buffer, and send message to server if buffer contains anything
-        ws::read_stdin_buffer(&mut sender, stdin_buffer.clone());
+        if input.close_at.map_or(false, |close_at| Instant::now() >= close_at) {
+            return match close::close(sender, events, session, options, output) {
+                Ok(code) => SessionEnd::Finished(close_exit_code(code)),
+                Err(err) => {
+                    stderr!("{}", err);
+                    SessionEnd::Finished(EXIT_CONNECTION_LOST)
+                }
+            };
+        }
 
         // Check if ping_interval has passed, if so, send a ping frame
-        last_time = ws::check_ping_interval(&ping_interval, last_time,
-                                            &mut sender, options.echo,
-                                            &options.ping_msg);
+        if let Err(err) = pinger.check(sender, output) {
+            return SessionEnd::Failed(err);
+        }
+
+        if goal.timed_out() {
+            stderr!("Timed out after {} seconds", options.timeout);
+            return finish(sender, events, session, options, output,
+                          EXIT_TIMEOUT);
+        }
+
+        let idle_at = idle_timeout
+            .and_then(|timeout| last_received.checked_add(timeout));
+        if idle_at.map_or(false, |idle_at| Instant::now() >= idle_at) {
+            let err = Error::IdleTimeout(options.idle_timeout);
+            stderr!("{}", err);
+            return SessionEnd::Failed(err);
+        }
 
-        // Sleep for 0.25 seconds at a time, to give the processor some rest.
-        // Should be a multiple of 1 second as this is the smallest possible
-        // ping_interval that can be input
-        thread::sleep(Duration::from_millis(250));
+        // Wait until there is something to do
+        let deadline = [pinger.deadline(), input.close_at, idle_at,
+                        goal.deadline].iter()
+            .filter_map(|deadline| *deadline)
+            .min();
+
+        let event = match ws::next_event(events, deadline) {
+            Some(event) => event,
+            None => continue
+        };
+
+        match event {
+            Event::Frame(frame) => {
+                if let Err(err) = ws::send_frame(sender, frame, output) {
+                    return SessionEnd::Failed(Error::Send(err));
+                }
+            },
+            Event::InputEnded => input.end(options),
+            Event::InputFailed(err) => {
+                stderr!("{}", error::describe(&err));
+                return finish(sender, events, session, options, output,
+                              err.exit_code());
+            },
+            Event::Signal(code) => {
+                return finish(sender, events, session, options, output, code);
+            },
+            Event::Reader(id, _) if id != session => {
+                log!(3, "Ignoring event from connection {}", id);
+            },
+            Event::Reader(_, ReaderEvent::Data(matched)) => {
+                last_received = Instant::now();
+
+                if goal.receive(matched) {
+                    log!(2, "Done after receiving {} messages", goal.received);
+                    return finish(sender, events, session, options, output, 0);
+                }
+            },
+            Event::Reader(_, ReaderEvent::Ping(payload)) => {
+                last_received = Instant::now();
+                if let Err(err) = ws::send_pong(sender, payload, output) {
+                    return SessionEnd::Failed(Error::Send(err));
+                }
+            },
+            Event::Reader(_, ReaderEvent::Pong(message, received)) => {
+                last_received = received;
+                let rtt = pinger.pong(received);
+                log!(2, "Pong received, round trip time: {:?}", rtt);
+
+                if options.print_pongs {
+                    output.pong(message, rtt);
+                }
+            },
+            Event::Reader(_, ReaderEvent::Close(code, reason)) => {
+                close::print_close(code, &reason);
+                close::answer_close(sender, code, output);
+                return SessionEnd::Closed(code);
+            },
+
+            // The reader thread is gone, the connection has been lost
+            Event::Reader(_, ReaderEvent::Disconnected) => {
+                return SessionEnd::Lost;
+            },
+            Event::Connected => {}
+        }
     }
 }
 
-/// Parses an Origin string from a websocket URL, replacing ws[s] with http[s].
-fn get_origin(url: &Url) -> String {
-    let scheme = if url.scheme() == "wss" {
-        "https"
-    } else {
-        "http"
-    };
+/// Close the connection, and have wsta exit with `code` whether or not the
+/// server answers the close frame
+fn finish(sender: &mut SenderObj,
+          events: &ChannelReceiver<Event>,
+          session: u64,
+          options: &Options,
+          output: &Output,
+          code: i32) -> SessionEnd {
+
+    if let Err(err) = close::close(sender, events, session, options, output) {
+        stderr!("{}", err);
+    }
 
-    format!("{}://{}", scheme, url.host_str().unwrap_or(""))
+    SessionEnd::Finished(code)
 }
 
-fn add_headers_to_request(request: &mut Request<WebSocketStream, WebSocketStream>,
-                          headers: &mut Vec<String>) {
+/// Try to connect to the server again, backing off exponentially between
+/// attempts. Exits with code 2 when `options.reconnect_attempts` is
+/// exhausted, or with code 15 when `goal` times out first.
+fn reconnect(options: &Options,
+             events: &ChannelReceiver<Event>,
+             input: &mut PendingInput,
+             goal: &Goal,
+             token: &mut Option<Token>) -> Session {
+
+    let max_delay = Duration::from_secs(options.reconnect_max_delay);
+    let mut delay = clamp_delay(Duration::from_secs(options.reconnect_delay),
+                                max_delay);
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
 
-    log!(2, "Adding headers to request: {:?}", headers);
-    for header in headers {
+        if options.reconnect_attempts > 0 &&
+           attempt > options.reconnect_attempts {
+            stderr!("Giving up after {} reconnection attempts",
+                    options.reconnect_attempts);
+            exit(EXIT_CONNECTION_LOST);
+        }
 
-        // Only process the header if it is a valid "key: value" header
-        if header.contains(':') {
+        stderr!("Reconnecting in {} seconds (attempt {})",
+                delay.as_secs(), attempt);
 
-            // Split by first colon into [key, value]
-            let split = header.splitn(2, ':').collect::<Vec<&str>>();
-            log!(3, "Split header: {:?}", split);
+        // Keep anything read while waiting. There is no connection to
+        // close, so signals exit right away.
+        let retry_at = Instant::now().checked_add(delay);
+        let wait_until = match (retry_at, goal.deadline) {
+            (Some(retry_at), Some(deadline)) => Some(min(retry_at, deadline)),
+            (retry_at, deadline) => retry_at.or(deadline)
+        };
 
-            let key = split[0];
-            log!(3, "Key is: {}", key);
+        while let Some(event) = ws::next_event(events, wait_until) {
+            match event {
+                Event::Frame(frame) => input.frames.push(frame),
+                Event::InputEnded => input.end(options),
+                Event::InputFailed(err) => exit_with(err),
+                Event::Signal(code) => exit(code),
+                Event::Reader(..) | Event::Connected => {}
+            }
+        }
 
-            let val = split[1].to_string().into_bytes();
-            log!(3, "Val is: {:?} (bytes)", val);
+        if goal.timed_out() {
+            stderr!("Timed out after {} seconds", options.timeout);
+            exit(EXIT_TIMEOUT);
+        }
 
-            // Write raw (untyped) header
-            request.headers.set_raw(format!("{}", key), vec![val]);
-            log!(2, "Wrote new header. Headers are now: {:?}", request.headers);
-        } else {
-            stderr!("Invalid header: {}. Must contain a colon (:)", header);
+        match connect_before(options, token, goal) {
+            Ok(client) => return client,
+            Err(err) => stderr!("Reconnection attempt {} failed: {}",
+                                attempt, describe_error(options, &err))
         }
+
+        delay = next_delay(delay, max_delay);
     }
 }
 
-fn send_messages(sender: &mut SenderObj<WebSocketStream>,
-                 messages: &mut Vec<String>,
-                 echo: bool) {
+/// Double the reconnection delay, without exceeding `max_delay`
+fn next_delay(delay: Duration, max_delay: Duration) -> Duration {
+    clamp_delay(delay.checked_mul(2).unwrap_or(max_delay), max_delay)
+}
 
-    for message in messages {
-        if echo {
-            println!("> {}", message);
-        }
+/// Limit a reconnection delay to `max_delay`. Delays are at least
+/// `MIN_RECONNECT_DELAY`, so that a delay of 0 does not reconnect in a
+/// busy loop.
+fn clamp_delay(delay: Duration, max_delay: Duration) -> Duration {
+    max(min(delay, max_delay), Duration::from_secs(MIN_RECONNECT_DELAY))
+}
+
+/// Connect to the server, printing where to once connected
+pub fn connect(options: &Options) -> Result<Session, Error> {
+    connect_reusing(options, &mut None)
+}
 
+/// Connect like `connect`, reusing the token from an earlier
+/// connection while it is valid
+fn connect_reusing(options: &Options,
+                   token: &mut Option<Token>) -> Result<Session, Error> {
+
+    let session = Session::connect_reusing(options, token)?;
+    stderr!("Connected to {}", options.url);
+
+    if let Some(protocol) = session.protocol() {
+        stderr!("Subprotocol: {}", protocol);
+    }
+
+    Ok(session)
+}
+
+/// Connect like `connect_reusing`, exiting with code 15 if `goal` times out
+/// first. Logging in, connecting and the handshakes can each block for
+/// minutes, so a watchdog thread enforces `--timeout` while they run.
+fn connect_before(options: &Options,
+                  token: &mut Option<Token>,
+                  goal: &Goal) -> Result<Session, Error> {
+
+    let (stop, stopped) = channel::<()>();
+
+    if let Some(deadline) = goal.deadline {
+        let timeout = options.timeout;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(remaining) {
+                stderr!("Timed out after {} seconds", timeout);
+                exit(EXIT_TIMEOUT);
+            }
+        });
+    }
+
+    let result = connect_reusing(options, token);
+    stop.send(()).unwrap_or(());
+    result
+}
+
+/// Describe `err` and what caused it, hinting at -I where the headers are
+/// likely to explain what went wrong
+pub fn describe_error(options: &Options, err: &Error) -> String {
+    log!(1, "Error object: {:?}", err);
+
+    match *err {
+        Error::Handshake(_) | Error::NoToken(_) |
+        Error::NoSessionCookie if !options.print_headers => {
+            format!("{}\nTry using -I for more info", error::describe(err))
+        },
+        _ => error::describe(err)
+    }
+}
+
+pub fn send_messages(sender: &mut SenderObj,
+                     messages: &Vec<String>,
+                     output: &Output) -> WebSocketResult<()> {
+
+    for message in messages {
         let frame = Message::text(message.as_str());
-        match sender.send_message(&frame) {
-            Err(err) => {
-                log!(1, "Error object: {:?}", err);
-                stderr!("An error occured while sending message {:?}: {}",
-                        message, err);
-                exit(1);
-            },
-            _ => {}
-        };
+        ws::send_message(sender, &frame, output)?;
     }
+
+    Ok(())
 }
 
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn next_delay_doubles_up_to_max() {
+    let max = Duration::from_secs(10);
+
+    assert_eq!(next_delay(Duration::from_secs(1), max), Duration::from_secs(2));
+    assert_eq!(next_delay(Duration::from_secs(4), max), Duration::from_secs(8));
+    assert_eq!(next_delay(Duration::from_secs(8), max), max);
+    assert_eq!(next_delay(max, max), max);
+    assert_eq!(next_delay(Duration::from_secs(u64::MAX), max), max);
+
+    // The first delay is limited too, and a delay of 0 still backs off
+    assert_eq!(clamp_delay(Duration::from_secs(120), max), max);
+    assert_eq!(clamp_delay(Duration::from_secs(0), max), Duration::from_secs(1));
+    assert_eq!(next_delay(Duration::from_secs(0), max), Duration::from_secs(1));
+    assert_eq!(next_delay(Duration::from_secs(0), Duration::from_secs(0)),
+               Duration::from_secs(1));
+}
+
+#[test]
+fn goal_is_reached_by_count_or_match() {
+    let mut options = Options::new();
+    options.count = 2;
+
+    let mut goal = Goal::from_options(&options);
+    assert!(!goal.receive(false));
+    assert!(goal.receive(false));
+
+    // Without --count, only a match ends the run
+    let mut goal = Goal::from_options(&Options::new());
+    assert!(!goal.receive(false));
+    assert!(goal.receive(true));
+    assert!(!goal.timed_out());
+}
@@ -0,0 +1,275 @@
+//! Recording of sessions to a file, and replaying of recorded sessions.
+//!
+//! A recording is a line-delimited log with one JSON object per frame:
+//!
+//! ```text
+//! {"direction":"out","opcode":"text","payload":"subscribe","time":12}
+//! {"direction":"in","opcode":"binary","payload":"AAEC","time":57}
+//! ```
+//!
+//! `time` is the number of milliseconds since the recording started, taken
+//! from a monotonic clock. Binary payloads are base64 encoded.
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender as ChannelSender;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::Json;
+use rustc_serialize::base64::{ToBase64, FromBase64, STANDARD};
+
+use websocket::Message;
+use websocket::message::Type;
+
+use wsta::Error;
+
+use frame_data::FrameData;
+use output::Direction;
+use ws::Event;
+
+/// A single frame read from a recording
+#[derive(Debug, PartialEq)]
+pub struct RecordedFrame {
+    pub time: u64,
+    pub direction: Direction,
+    pub frame: FrameData
+}
+
+/// Writes frames to a recording file. Cloning a Recorder is cheap, and all
+/// clones write to the same file, so it can be shared between the threads
+/// sending and receiving frames.
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+    start: Instant
+}
+
+/// Create a Recorder writing to `path`, or None if `path` is empty
+pub fn create_recorder(path: &str) -> Result<Option<Recorder>, Error> {
+    if path.is_empty() {
+        Ok(None)
+    } else {
+        Recorder::create(path).map(Some)
+    }
+}
+
+impl Recorder {
+
+    /// Create the recording file
+    pub fn create(path: &str) -> Result<Recorder, Error> {
+        let file = File::create(path).map_err(|err| {
+            Error::Io(format!("Could not create recording file '{}'", path), err)
+        })?;
+
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(file)),
+            start: Instant::now()
+        })
+    }
+
+    /// Append a text or binary message to the recording. Control frames are
+    /// not recorded.
+    pub fn record(&self, direction: Direction, message: &Message) {
+        let elapsed = self.start.elapsed();
+        let time = elapsed.as_secs() * 1000 +
+                   (elapsed.subsec_nanos() / 1000000) as u64;
+
+        let frame = match message.opcode {
+            Type::Text => FrameData::from_utf8(
+                String::from_utf8_lossy(&message.payload).into_owned()),
+            Type::Binary => FrameData::from_binary_buffer(
+                message.payload.clone().into_owned()),
+            _ => return
+        };
+
+        let line = encode_frame(time, direction, &frame);
+        log!(4, "Recording: {}", line);
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(err) = writeln!(file, "{}", line) {
+            stderr!("Failed to write to recording file: {}", err);
+            log!(1, "Error: {:?}", err);
+        }
+    }
+}
+
+/// Replay the outgoing frames of a recording by sending them to `events`
+/// with the same relative delays as when they were recorded. The delays are
+/// divided by `speed`, so 2.0 replays twice as fast.
+///
+/// This replaces the stdin reader, and the end of the recording is treated
+/// like EOF in stdin. Fails if the recording cannot be read.
+pub fn spawn_replay(path: &str, speed: f64,
+                    events: ChannelSender<Event>) -> Result<(), Error> {
+
+    if !(speed > 0.0) {
+        return Err(Error::InvalidArgument(format!("Replay speed must be a \
+                                                   positive number, got {}",
+                                                  speed)));
+    }
+
+    let frames = read_recording(path)?
+        .into_iter()
+        .filter(|recorded| recorded.direction == Direction::Outgoing)
+        .collect::<Vec<_>>();
+    log!(2, "Replaying {} frames from {}", frames.len(), path);
+
+    thread::spawn(move || {
+        log!(3, "Replay thread spawned");
+        let start = Instant::now();
+
+        for recorded in frames {
+            let due = scale_delay(recorded.time, speed);
+            let elapsed = start.elapsed();
+
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+
+            events.send(Event::Frame(recorded.frame)).unwrap_or(());
+        }
+
+        stderr!("Replay finished");
+        events.send(Event::InputEnded).unwrap_or(());
+    });
+
+    Ok(())
+}
+
+/// Read every frame from a recording file, failing on invalid lines
+pub fn read_recording(path: &str) -> Result<Vec<RecordedFrame>, Error> {
+    let file = File::open(path).map_err(|err| {
+        Error::Io(format!("Could not open recording file '{}'", path), err)
+    })?;
+
+    let mut frames = Vec::new();
+
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| {
+            Error::Io(format!("Could not read recording file '{}'", path), err)
+        })?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match decode_frame(&line) {
+            Ok(frame) => frames.push(frame),
+            Err(err) => {
+                return Err(Error::InvalidArgument(format!(
+                    "Invalid frame on line {} of '{}': {}", number + 1, path, err)));
+            }
+        }
+    }
+
+    Ok(frames)
+}
+
+/// The time at which a frame recorded at `time` milliseconds should be sent
+fn scale_delay(time: u64, speed: f64) -> Duration {
+    let millis = (time as f64 / speed) as u64;
+    Duration::from_millis(millis)
+}
+
+/// Serialize a frame to a single line of JSON
+fn encode_frame(time: u64, direction: Direction, frame: &FrameData) -> String {
+    let mut object = BTreeMap::new();
+
+    object.insert(String::from("time"), Json::U64(time));
+    object.insert(String::from("direction"),
+                  Json::String(String::from(direction.as_str())));
+
+    match frame.utf8 {
+        Some(ref text) => {
+            object.insert(String::from("opcode"),
+                          Json::String(String::from("text")));
+            object.insert(String::from("payload"), Json::String(text.clone()));
+        },
+        None => {
+            let binary = frame.binary.as_ref().map(|b| &b[..]).unwrap_or(&[]);
+            object.insert(String::from("opcode"),
+                          Json::String(String::from("binary")));
+            object.insert(String::from("payload"),
+                          Json::String(binary.to_base64(STANDARD)));
+        }
+    }
+
+    Json::Object(object).to_string()
+}
+
+/// Parse a single line of a recording
+fn decode_frame(line: &str) -> Result<RecordedFrame, String> {
+    let json = Json::from_str(line).map_err(|err| format!("{}", err))?;
+
+    let time = json.find("time")
+        .and_then(|time| time.as_u64())
+        .ok_or("missing or invalid \"time\"")?;
+
+    let direction = match json.find("direction").and_then(|d| d.as_string()) {
+        Some("out") => Direction::Outgoing,
+        Some("in") => Direction::Incoming,
+        _ => return Err(String::from("missing or invalid \"direction\""))
+    };
+
+    let payload = json.find("payload")
+        .and_then(|payload| payload.as_string())
+        .ok_or("missing or invalid \"payload\"")?;
+
+    let frame = match json.find("opcode").and_then(|o| o.as_string()) {
+        Some("text") => FrameData::from_utf8(String::from(payload)),
+        Some("binary") => {
+            let binary = payload.from_base64()
+                .map_err(|err| format!("invalid base64 payload: {}", err))?;
+            FrameData::from_binary_buffer(binary)
+        },
+        _ => return Err(String::from("missing or invalid \"opcode\""))
+    };
+
+    Ok(RecordedFrame {
+        time: time,
+        direction: direction,
+        frame: frame
+    })
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn frames_survive_encoding() {
+    let text = FrameData::from_utf8(String::from("hello \"world\"\n"));
+    let line = encode_frame(12, Direction::Outgoing, &text);
+    assert_eq!(line, concat!(r#"{"direction":"out","opcode":"text","#,
+                             r#""payload":"hello \"world\"\n","time":12}"#));
+    assert_eq!(decode_frame(&line), Ok(RecordedFrame {
+        time: 12,
+        direction: Direction::Outgoing,
+        frame: FrameData::from_utf8(String::from("hello \"world\"\n"))
+    }));
+
+    let binary = FrameData::from_binary_buffer(vec![0, 1, 2, 255]);
+    let line = encode_frame(57, Direction::Incoming, &binary);
+    assert_eq!(decode_frame(&line), Ok(RecordedFrame {
+        time: 57,
+        direction: Direction::Incoming,
+        frame: FrameData::from_binary_buffer(vec![0, 1, 2, 255])
+    }));
+}
+
+#[test]
+fn invalid_frames_are_rejected() {
+    assert!(decode_frame("not json").is_err());
+    assert!(decode_frame(r#"{"direction":"out","opcode":"text"}"#).is_err());
+    assert!(decode_frame(concat!(r#"{"direction":"sideways","#,
+                                 r#""opcode":"text","payload":"","time":0}"#))
+            .is_err());
+}
+
+#[test]
+fn replay_delay_is_scaled() {
+    assert_eq!(scale_delay(1000, 1.0), Duration::from_millis(1000));
+    assert_eq!(scale_delay(1000, 2.0), Duration::from_millis(500));
+    assert_eq!(scale_delay(1000, 0.5), Duration::from_millis(2000));
+}
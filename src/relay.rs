@@ -0,0 +1,149 @@
+//! Relay mode, where wsta sits between WebSocket clients and the server at
+//! `url`, forwarding frames in both directions and printing them to stdout.
+//!
+//! Frames sent by the client are prefixed with "> ", like `--echo` does for
+//! outgoing frames, and frames sent by the server are prefixed with "< ".
+//! With `--output-format jsonl`, frames sent by the client have the "out"
+//! direction, and frames sent by the server have the "in" direction.
+use std::io;
+use std::io::Write;
+use std::thread;
+
+use websocket::{Server, Message, Sender, Receiver};
+use wsta::deflate::Sender as SenderObj;
+use wsta::deflate::Receiver as ReceiverObj;
+use websocket::message::Type;
+use websocket::server::Connection;
+use websocket::stream::WebSocketStream;
+
+use ws;
+use wsta::Error;
+use wsta::options::{Options, OutputFormat};
+use output::{Output, Direction};
+use program::{connect, describe_error, parse_url, exit_with};
+use server::upgrade_client;
+
+pub fn run_relay(options: &Options) {
+
+    // Fail early rather than on the first client
+    parse_url(&options.url);
+
+    log!(2, "About to bind to {}", options.listen);
+    let server = match Server::bind(options.listen.as_str()) {
+        Ok(server) => server,
+        Err(err) => {
+            log!(1, "Error object: {:?}", err);
+            exit_with(Error::Bind(options.listen.clone(), err));
+        }
+    };
+
+    match server.local_addr() {
+        Ok(addr) => stderr!("Relaying {} to {}", addr, options.url),
+        Err(_) => stderr!("Relaying {} to {}", options.listen, options.url)
+    }
+
+    let output = Output::from_options(options).unwrap_or_else(|err| exit_with(err));
+
+    for connection in server {
+        match connection {
+            Ok(connection) => spawn_relay(connection, options.clone(),
+                                          output.clone()),
+            Err(err) => stderr!("Failed to accept connection: {}", err)
+        }
+    }
+}
+
+/// Upgrade a newly connected client and open a matching connection to the
+/// server. Each client is handled in its own thread, as connecting to the
+/// server may take a while.
+fn spawn_relay(connection: Connection<WebSocketStream, WebSocketStream>,
+               options: Options,
+               output: Output) {
+
+    thread::spawn(move || {
+        log!(3, "Relay thread spawned");
+
+        // The -H headers are meant for the server, so nothing extra
+        // is added to the response sent to the client
+        let (client, addr) = match upgrade_client(connection,
+                                                  options.print_headers,
+                                                  &Vec::new()) {
+            Ok(result) => result,
+            Err(err) => {
+                stderr!("{}", err);
+                return;
+            }
+        };
+
+        let upstream = match connect(&options) {
+            Ok(upstream) => upstream,
+            Err(err) => {
+                stderr!("{}", describe_error(&options, &err));
+                stderr!("Closing connection from {}", addr);
+                return;
+            }
+        };
+
+        let (client_sender, client_receiver) = client.split();
+        let (upstream_sender, upstream_receiver) = upstream.split();
+
+        let upstream_output = output.clone();
+        let upstream_pump = thread::spawn(move || {
+            pump(upstream_receiver, client_sender,
+                 Direction::Incoming, &upstream_output);
+        });
+
+        pump(client_receiver, upstream_sender, Direction::Outgoing, &output);
+        upstream_pump.join().unwrap_or(());
+
+        stderr!("Client {} disconnected", addr);
+    });
+}
+
+/// Forward every message from `receiver` to `sender`, printing data frames
+/// to stdout. When either side goes away, the connection of `sender` is shut
+/// down as well, so that the pump running in the opposite direction ends too.
+fn pump(mut receiver: ReceiverObj,
+        mut sender: SenderObj,
+        direction: Direction,
+        output: &Output) {
+
+    for message in receiver.incoming_messages() {
+        let message: Message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                log!(1, "Error: {:?}", err);
+                break;
+            }
+        };
+
+        let is_close = message.opcode == Type::Close;
+
+        if let Err(err) = sender.send_message(&message) {
+            log!(1, "Error object: {:?}", err);
+            stderr!("An error occured while relaying message: {}", err);
+            break;
+        }
+
+        output.record(direction, &message);
+
+        match (output.format, message.opcode) {
+            (OutputFormat::JsonLines, _) => output.print(direction, message),
+            (_, Type::Text) | (_, Type::Binary) => {
+                let prefix = match direction {
+                    Direction::Outgoing => "> ",
+                    Direction::Incoming => "< "
+                };
+
+                ws::message_to_stdout(message, prefix);
+            },
+            _ => log!(3, "Relayed control frame: {:?}", message)
+        }
+
+        if is_close {
+            break;
+        }
+    }
+
+    sender.shutdown_all().unwrap_or(());
+}
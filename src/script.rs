@@ -0,0 +1,680 @@
+//! Conversation scripts, run with `--script`. A script is a list of steps,
+//! one per line, which are run in order against the connection:
+//!
+//! ```text
+//! # Subscribe, and wait for the first update
+//! send "{\"type\": \"subscribe\", \"channel\": \"prices\"}"
+//! expect /subscribed/ within 2s
+//! expect-json .data.prices[0].currency == "EUR"
+//! send-binary @frame.bin
+//! sleep 500ms
+//! close 1000
+//! ```
+//!
+//! `expect` and `expect-json` wait for a text or binary frame that matches,
+//! skipping any that do not, for 5 seconds unless `within` says otherwise.
+//! Frames received during a `sleep` are kept for the steps that follow.
+//!
+//! The result of every step is printed to stdout in the Test Anything
+//! Protocol, and the script stops at the first step that fails.
+use std::io;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::path::Path;
+use std::str;
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver as ChannelReceiver, RecvTimeoutError};
+use std::thread;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use rustc_serialize::json::Json;
+
+use websocket::{Message, Sender, Receiver};
+use websocket::message::Type;
+
+use wsta::Error;
+use wsta::Session;
+use wsta::deflate::Sender as SenderObj;
+use wsta::error;
+use wsta::error::EXIT_SCRIPT_FAILED;
+use wsta::json_path::JsonPath;
+use wsta::options::Options;
+
+use close;
+use program::{connect, describe_error, exit_with};
+
+/// How long `expect` and `expect-json` wait without `within`
+const DEFAULT_WITHIN: u64 = 5;
+
+/// A single step of a script
+#[derive(Debug)]
+enum Step {
+
+    /// Send a text frame
+    Send(String),
+
+    /// Send a binary frame with the contents of a file
+    SendBinary(Vec<u8>),
+
+    /// Wait for a frame matching the regular expression
+    Expect(Regex, Duration),
+
+    /// Wait for a JSON frame with a value at the path that compares to the
+    /// given value
+    ExpectJson(JsonPath, Comparison, Json, Duration),
+
+    /// Wait without sending anything
+    Sleep(Duration),
+
+    /// Close the connection with the given code and reason
+    Close(u16, String)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual
+}
+
+/// A step along with the line it was read from, for the report
+#[derive(Debug)]
+struct ScriptLine {
+    number: usize,
+    source: String,
+    step: Step
+}
+
+/// What the reader thread receives
+enum Incoming {
+    Message(Message<'static>),
+
+    /// The server sent a close frame with the given code and reason
+    Closed(Option<u16>, String),
+
+    /// The connection was lost, for the given reason
+    Lost(String)
+}
+
+/// The connection a script is run against
+struct Conversation {
+    sender: SenderObj,
+    incoming: ChannelReceiver<Incoming>,
+
+    /// Frames received while sleeping, which the next steps may expect
+    pending: VecDeque<Message<'static>>,
+
+    /// Why the connection ended, once it has
+    ended: Option<String>
+}
+
+pub fn run_script(options: &Options) {
+
+    let lines = load_script(&options.script).unwrap_or_else(|err| exit_with(err));
+
+    let session = match connect(options) {
+        Ok(session) => session,
+        Err(err) => {
+            stderr!("{}", describe_error(options, &err));
+            exit(err.exit_code());
+        }
+    };
+
+    let mut conversation = Conversation::new(session);
+
+    // Messages given on the command line go out before the first step
+    for message in &options.messages {
+        if let Err(err) = conversation.send(&Message::text(message.as_str())) {
+            stderr!("{}", err);
+            exit(EXIT_SCRIPT_FAILED);
+        }
+    }
+
+    println!("1..{}", lines.len());
+
+    for (index, line) in lines.iter().enumerate() {
+        let description = format!("line {}: {}", line.number, line.source);
+
+        if let Err(reason) = conversation.run(&line.step, options) {
+            println!("not ok {} - {}", index + 1, description);
+            println!("# {}", reason);
+
+            for (skipped, line) in lines.iter().enumerate().skip(index + 1) {
+                println!("ok {} - line {}: {} # SKIP previous step failed",
+                         skipped + 1, line.number, line.source);
+            }
+
+            io::stdout().flush().unwrap_or(());
+            conversation.shutdown();
+            exit(EXIT_SCRIPT_FAILED);
+        }
+
+        println!("ok {} - {}", index + 1, description);
+        io::stdout().flush().unwrap_or(());
+    }
+
+    // Close the connection if the script did not
+    if conversation.ended.is_none() {
+        conversation.close(options.close_code, &options.close_reason, options)
+            .unwrap_or_else(|err| log!(1, "Closing failed: {}", err));
+    }
+
+    conversation.shutdown();
+}
+
+/// Read and parse the script at `path`. Files sent with `send-binary` are
+/// relative to the directory of the script.
+fn load_script(path: &str) -> Result<Vec<ScriptLine>, Error> {
+    let mut text = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut text))
+        .map_err(|err| Error::Io(format!("Could not read script '{}'", path), err))?;
+
+    let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+
+    parse_script(&text, dir).map_err(|err| {
+        Error::InvalidArgument(format!("Invalid script '{}', {}", path, err))
+    })
+}
+
+/// Parse every line of a script. Empty lines and lines starting with `#`
+/// are skipped.
+fn parse_script(text: &str, dir: &Path) -> Result<Vec<ScriptLine>, String> {
+    let mut lines = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let step = parse_step(line, dir)
+            .map_err(|err| format!("line {}: {}", index + 1, err))?;
+
+        lines.push(ScriptLine {
+            number: index + 1,
+            source: String::from(line),
+            step: step
+        });
+    }
+
+    Ok(lines)
+}
+
+fn parse_step(line: &str, dir: &Path) -> Result<Step, String> {
+    let (keyword, rest) = split_word(line);
+
+    match keyword {
+        "send" => Ok(Step::Send(parse_string(rest)?)),
+        "send-binary" => {
+            if !rest.starts_with('@') {
+                return Err(String::from("send-binary takes a file, as in \
+                                         send-binary @frame.bin"));
+            }
+
+            let path = dir.join(&rest[1..]);
+            let mut data = Vec::new();
+            File::open(&path).and_then(|mut file| file.read_to_end(&mut data))
+                .map_err(|err| format!("could not read '{}': {}",
+                                       path.display(), err))?;
+
+            Ok(Step::SendBinary(data))
+        },
+        "expect" => {
+            let (rest, within) = split_within(rest)?;
+            Ok(Step::Expect(parse_regex(rest)?, within))
+        },
+        "expect-json" => {
+            let (rest, within) = split_within(rest)?;
+            let (path, rest) = split_word(rest);
+            let (comparison, value) = split_word(rest);
+
+            let comparison = match comparison {
+                "==" => Comparison::Equal,
+                "!=" => Comparison::NotEqual,
+                _ => return Err(String::from("expect-json compares with == \
+                                              or !=, as in expect-json \
+                                              .status == \"ok\""))
+            };
+
+            let value = Json::from_str(value)
+                .map_err(|_| format!("invalid JSON value '{}'. Strings must \
+                                      be quoted", value))?;
+
+            Ok(Step::ExpectJson(JsonPath::parse(path)?, comparison, value, within))
+        },
+        "sleep" => Ok(Step::Sleep(parse_duration(rest)?)),
+        "close" => {
+            let (code, reason) = split_word(rest);
+
+            let code = match code {
+                "" => close::NORMAL_CLOSURE,
+                code => code.parse::<u16>().ok()
+                    .filter(|code| close::is_valid_close_code(*code))
+                    .ok_or(format!("invalid close code '{}'", code))?
+            };
+
+            let reason = match reason {
+                "" => String::new(),
+                reason => parse_string(reason)?
+            };
+
+            Ok(Step::Close(code, reason))
+        },
+        _ => Err(format!("unknown step '{}'", keyword))
+    }
+}
+
+/// Split off the first word of `text`
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, "")
+    }
+}
+
+/// Split off a trailing `within DURATION`
+fn split_within(text: &str) -> Result<(&str, Duration), String> {
+    let text = text.trim();
+    let default = Duration::from_secs(DEFAULT_WITHIN);
+
+    let (head, duration) = match text.rfind(char::is_whitespace) {
+        Some(start) => (text[..start].trim_end(), &text[start..]),
+        None => return Ok((text, default))
+    };
+
+    match head.rfind(char::is_whitespace) {
+        Some(start) if head[start..].trim() == "within" => {
+            Ok((head[..start].trim_end(), parse_duration(duration)?))
+        },
+        _ => Ok((text, default))
+    }
+}
+
+/// Parse a string, quoted and escaped as in JSON
+fn parse_string(text: &str) -> Result<String, String> {
+    match Json::from_str(text) {
+        Ok(Json::String(string)) => Ok(string),
+        _ => Err(format!("expected a quoted string, got '{}'", text))
+    }
+}
+
+/// Parse a regular expression between slashes, as in `/ack \d+/`
+fn parse_regex(text: &str) -> Result<Regex, String> {
+    if text.len() < 2 || !text.starts_with('/') || !text.ends_with('/') {
+        return Err(format!("expected a regular expression between slashes, \
+                            got '{}'", text));
+    }
+
+    let pattern = text[1..text.len() - 1].replace("\\/", "/");
+    Regex::new(&pattern)
+        .map_err(|err| format!("invalid regular expression '{}': {}",
+                               pattern, err))
+}
+
+/// Parse a duration such as `500ms`, `5s` or `1m`. Without a unit, the
+/// number is taken as seconds.
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+
+    let (number, millis) = if text.ends_with("ms") {
+        (&text[..text.len() - 2], 1.0)
+    } else if text.ends_with('s') {
+        (&text[..text.len() - 1], 1000.0)
+    } else if text.ends_with('m') {
+        (&text[..text.len() - 1], 60000.0)
+    } else {
+        (text, 1000.0)
+    };
+
+    match number.parse::<f64>() {
+        Ok(number) if number >= 0.0 => {
+            Ok(Duration::from_millis((number * millis) as u64))
+        },
+        _ => Err(format!("invalid duration '{}'. Use durations such as \
+                          500ms, 5s or 1m", text))
+    }
+}
+
+/// Describe a duration the way it would be written in a script
+fn describe_duration(duration: Duration) -> String {
+    match duration.subsec_nanos() {
+        0 => format!("{}s", duration.as_secs()),
+        _ => format!("{}ms", duration.as_secs() * 1000 +
+                             duration.subsec_millis() as u64)
+    }
+}
+
+/// Whether the payload of a frame is JSON with a value at `path` that
+/// compares to `expected`
+fn json_matches(payload: &[u8],
+                path: &JsonPath,
+                comparison: Comparison,
+                expected: &Json) -> bool {
+
+    let json = match str::from_utf8(payload).ok()
+        .and_then(|text| Json::from_str(text).ok()) {
+        Some(json) => json,
+        None => return false
+    };
+
+    // The path must exist for either comparison
+    match (path.find(&json), comparison) {
+        (Some(actual), Comparison::Equal) => json_equal(actual, expected),
+        (Some(actual), Comparison::NotEqual) => !json_equal(actual, expected),
+        (None, _) => false
+    }
+}
+
+/// Compare JSON values, treating numbers as equal whatever their type, so
+/// that 1 and 1.0 are the same
+fn json_equal(a: &Json, b: &Json) -> bool {
+    match (a, b) {
+        (&Json::Array(ref a), &Json::Array(ref b)) => {
+            a.len() == b.len() &&
+            a.iter().zip(b.iter()).all(|(a, b)| json_equal(a, b))
+        },
+        (&Json::Object(ref a), &Json::Object(ref b)) => {
+            a.len() == b.len() &&
+            a.iter().all(|(key, a)| b.get(key).map_or(false, |b| json_equal(a, b)))
+        },
+        _ if a.is_number() && b.is_number() => a.as_f64() == b.as_f64(),
+        _ => a == b
+    }
+}
+
+impl Conversation {
+
+    /// Take over the connection of `session`, reading from it in a thread
+    /// of its own
+    fn new(session: Session) -> Conversation {
+        let (sender, mut receiver) = session.split();
+        let (incoming_tx, incoming) = channel();
+
+        thread::spawn(move || {
+            log!(3, "Script reader thread spawned");
+
+            for message in receiver.incoming_messages() {
+                let message: Message<'static> = match message {
+                    Ok(message) => message,
+                    Err(err) => {
+                        log!(1, "Error: {:?}", err);
+                        let reason = error::describe(&Error::WebSocket(err));
+                        incoming_tx.send(Incoming::Lost(reason)).unwrap_or(());
+                        break;
+                    }
+                };
+
+                if message.opcode == Type::Close {
+                    let reason = String::from_utf8_lossy(&message.payload)
+                        .into_owned();
+                    incoming_tx.send(Incoming::Closed(message.cd_status_code,
+                                                      reason))
+                        .unwrap_or(());
+                    break;
+                }
+
+                incoming_tx.send(Incoming::Message(message)).unwrap_or(());
+            }
+        });
+
+        Conversation {
+            sender: sender,
+            incoming: incoming,
+            pending: VecDeque::new(),
+            ended: None
+        }
+    }
+
+    /// Run a single step
+    fn run(&mut self, step: &Step, options: &Options) -> Result<(), String> {
+        match *step {
+            Step::Send(ref text) => self.send(&Message::text(text.as_str())),
+            Step::SendBinary(ref data) => {
+                self.send(&Message::binary(data.as_slice()))
+            },
+            Step::Expect(ref regex, within) => {
+                self.expect(within, |payload| {
+                    regex.is_match(&String::from_utf8_lossy(payload))
+                })
+            },
+            Step::ExpectJson(ref path, comparison, ref expected, within) => {
+                self.expect(within, |payload| {
+                    json_matches(payload, path, comparison, expected)
+                })
+            },
+            Step::Sleep(duration) => {
+                self.sleep(duration);
+                Ok(())
+            },
+            Step::Close(code, ref reason) => self.close(code, reason, options)
+        }
+    }
+
+    fn send(&mut self, message: &Message) -> Result<(), String> {
+        if let Some(ref ended) = self.ended {
+            return Err(ended.clone());
+        }
+
+        log!(4, "Sending: {:?}", message);
+        self.sender.send_message(message)
+            .map_err(|err| error::describe(&Error::Send(err)))
+    }
+
+    /// Wait up to `within` for a text or binary frame whose payload
+    /// `matches`, skipping any that do not
+    fn expect<F>(&mut self, within: Duration, matches: F) -> Result<(), String>
+        where F: Fn(&[u8]) -> bool {
+
+        let deadline = Instant::now() + within;
+        let mut skipped: Option<(usize, Message)> = None;
+
+        loop {
+            let message = match self.pending.pop_front() {
+                Some(message) => message,
+                None => match self.receive(deadline)? {
+                    Some(message) => message,
+                    None => break
+                }
+            };
+
+            if matches(&message.payload) {
+                return Ok(());
+            }
+
+            log!(2, "Frame did not match: {:?}", message);
+            let count = skipped.map_or(0, |(count, _)| count);
+            skipped = Some((count + 1, message));
+        }
+
+        let mut reason = format!("No matching frame within {}",
+                                 describe_duration(within));
+
+        if let Some((count, last)) = skipped {
+            let payload = String::from_utf8_lossy(&last.payload);
+            let payload: String = payload.chars().take(200).collect();
+            let frames = if count == 1 { "frame" } else { "frames" };
+            reason.push_str(&format!(". {} {} did not match, the last one \
+                                      was: {}", count, frames, payload));
+        }
+
+        Err(reason)
+    }
+
+    /// Wait for `duration`, keeping the frames received in the meantime
+    fn sleep(&mut self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+
+        // Should the connection end, the next step will tell
+        while let Ok(Some(message)) = self.receive(deadline) {
+            self.pending.push_back(message);
+        }
+
+        let now = Instant::now();
+        if deadline > now {
+            thread::sleep(deadline - now);
+        }
+    }
+
+    /// Close the connection, and wait up to `--close-timeout` seconds for
+    /// the server to answer
+    fn close(&mut self, code: u16, reason: &str, options: &Options)
+             -> Result<(), String> {
+
+        self.send(&Message::close_because(code, reason))?;
+        self.ended = Some(String::from("The connection was closed by the \
+                                        script"));
+
+        let deadline = Instant::now() +
+                       Duration::from_secs(options.close_timeout);
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format!("No close frame received within {} \
+                                    seconds", options.close_timeout));
+            }
+
+            match self.incoming.recv_timeout(deadline - now) {
+                Ok(Incoming::Message(message)) => {
+                    log!(3, "Dropping frame while closing: {:?}", message);
+                },
+                Ok(Incoming::Closed(..)) => return Ok(()),
+                Ok(Incoming::Lost(reason)) => return Err(reason),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(String::from("Connection lost"));
+                }
+            }
+        }
+    }
+
+    /// Wait until `deadline` for the next text or binary frame, answering
+    /// pings in the meantime
+    ///
+    /// # Returns
+    /// Returns None if the deadline passed first, or why the connection
+    /// ended if it did.
+    fn receive(&mut self, deadline: Instant)
+               -> Result<Option<Message<'static>>, String> {
+        loop {
+            if let Some(ref ended) = self.ended {
+                return Err(ended.clone());
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            match self.incoming.recv_timeout(deadline - now) {
+                Ok(Incoming::Message(message)) => match message.opcode {
+                    Type::Text | Type::Binary => return Ok(Some(message)),
+                    Type::Ping => {
+                        let pong = Message::pong(message.payload.into_owned());
+                        self.sender.send_message(&pong).unwrap_or(());
+                    },
+                    _ => log!(3, "Skipping frame: {:?}", message)
+                },
+                Ok(Incoming::Closed(code, reason)) => {
+                    close::print_close(code, &reason);
+
+                    let reply = match code {
+                        Some(code) => Message::close_because(code, ""),
+                        None => Message::close()
+                    };
+                    self.sender.send_message(&reply).unwrap_or(());
+
+                    self.ended = Some(format!("{}", Error::Closed(code, reason)));
+                },
+                Ok(Incoming::Lost(reason)) => self.ended = Some(reason),
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.ended = Some(String::from("Connection lost"));
+                }
+            }
+        }
+    }
+
+    /// Make sure the reader thread lets go of the socket
+    fn shutdown(&mut self) {
+        self.sender.shutdown_all().unwrap_or(());
+    }
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn steps_are_parsed() {
+    let script = "# A comment\n\
+                  send \"hello \\\"world\\\"\"\n\
+                  \n\
+                  expect /ack \\d+\\/ok/ within 2s\n\
+                  expect-json .items[0].id != null\n\
+                  sleep 500ms\n\
+                  close 4000 \"done\"\n";
+    let lines = parse_script(script, Path::new(".")).unwrap();
+
+    assert_eq!(lines.iter().map(|line| line.number).collect::<Vec<_>>(),
+               vec![2, 4, 5, 6, 7]);
+
+    match lines[0].step {
+        Step::Send(ref text) => assert_eq!(text, "hello \"world\""),
+        ref step => panic!("Unexpected step {:?}", step)
+    }
+
+    match lines[1].step {
+        Step::Expect(ref regex, within) => {
+            assert!(regex.is_match("ack 12/ok"));
+            assert_eq!(within, Duration::from_secs(2));
+        },
+        ref step => panic!("Unexpected step {:?}", step)
+    }
+
+    match lines[2].step {
+        Step::ExpectJson(ref path, comparison, ref value, within) => {
+            assert_eq!(path.to_string(), ".items[0].id");
+            assert_eq!(comparison, Comparison::NotEqual);
+            assert_eq!(value, &Json::Null);
+            assert_eq!(within, Duration::from_secs(DEFAULT_WITHIN));
+        },
+        ref step => panic!("Unexpected step {:?}", step)
+    }
+
+    match (&lines[3].step, &lines[4].step) {
+        (&Step::Sleep(duration), &Step::Close(code, ref reason)) => {
+            assert_eq!(duration, Duration::from_millis(500));
+            assert_eq!((code, &reason[..]), (4000, "done"));
+        },
+        steps => panic!("Unexpected steps {:?}", steps)
+    }
+}
+
+#[test]
+fn invalid_steps_are_rejected() {
+    let parse = |line| parse_script(line, Path::new("."));
+
+    assert_eq!(parse("shout \"hi\"").unwrap_err(), "line 1: unknown step 'shout'");
+    assert!(parse("send hello").is_err());
+    assert!(parse("expect ack").is_err());
+    assert!(parse("expect /ack/ within soon").is_err());
+    assert!(parse("expect-json status == \"ok\"").is_err());
+    assert!(parse("expect-json .status == ok").is_err());
+    assert!(parse("close 1005").is_err());
+}
+
+#[test]
+fn json_frames_are_matched() {
+    let path = JsonPath::parse(".data.prices[1]").unwrap();
+    let payload = br#"{"data": {"prices": [1, 2.0, 3]}}"#;
+
+    assert!(json_matches(payload, &path, Comparison::Equal, &Json::U64(2)));
+    assert!(json_matches(payload, &path, Comparison::NotEqual, &Json::U64(3)));
+    assert!(!json_matches(b"not json", &path, Comparison::NotEqual, &Json::Null));
+
+    let missing = JsonPath::parse(".data.volume").unwrap();
+    assert!(!json_matches(payload, &missing, Comparison::NotEqual, &Json::Null));
+}
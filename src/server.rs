@@ -0,0 +1,311 @@
+//! Listen mode, where wsta accepts WebSocket clients instead of connecting
+//! to a server. Frames from any client are printed to stdout, and stdin is
+//! sent to every connected client.
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::process::exit;
+
+use websocket::{Client, Server, Sender};
+use websocket::server::Connection;
+use websocket::stream::WebSocketStream;
+
+use ws;
+use ws::{Event, ReaderEvent};
+use close;
+use wsta::Error;
+use wsta::options::Options;
+use frame_data::FrameData;
+use wsta::deflate::Sender as SenderObj;
+use wsta::deflate::Receiver as ReceiverObj;
+use wsta::http::{print_headers, add_headers};
+use wsta::session::WsClient;
+use program::{send_messages, spawn_input, exit_with};
+use output::Output;
+
+/// A client connected to the server
+struct Peer {
+
+    /// Identifies the events sent by the reader thread of the client
+    id: u64,
+
+    /// Where the client connected from, for logging
+    addr: String,
+
+    /// Used to send frames read from stdin to the client
+    sender: SenderObj
+}
+
+pub fn run_server(options: &Options) {
+
+    log!(2, "About to bind to {}", options.listen);
+    let server = match Server::bind(options.listen.as_str()) {
+        Ok(server) => server,
+        Err(err) => {
+            log!(1, "Error object: {:?}", err);
+            exit_with(Error::Bind(options.listen.clone(), err));
+        }
+    };
+
+    match server.local_addr() {
+        Ok(addr) => stderr!("Listening on {}", addr),
+        Err(_) => stderr!("Listening on {}", options.listen)
+    }
+
+    let output = Output::from_options(options).unwrap_or_else(|err| exit_with(err));
+    let (event_tx, events) = channel();
+
+    let peers = Arc::new(Mutex::new(Vec::<Peer>::new()));
+    spawn_acceptor(server, options.clone(), peers.clone(), event_tx.clone(),
+                   output.clone());
+
+    spawn_input(options, event_tx).unwrap_or_else(|err| exit_with(err));
+
+    // Frames read while no client is connected are kept until one connects
+    let mut pending = Vec::new();
+
+    log!(3, "Entering main loop");
+    loop {
+        let event = match ws::next_event(&events, None) {
+            Some(event) => event,
+            None => continue
+        };
+
+        let mut peers = peers.lock().unwrap();
+
+        match event {
+            Event::Frame(frame) => {
+                pending.push(frame);
+                broadcast(&mut peers, &mut pending, &output);
+            },
+            Event::Connected => broadcast(&mut peers, &mut pending, &output),
+            Event::Reader(id, event) => handle_event(&mut peers, id, event,
+                                                     &output),
+
+            // Clients may keep sending frames after stdin has ended
+            Event::InputEnded => log!(2, "Reached the end of the input"),
+            Event::InputFailed(err) => exit_with(err),
+            Event::Signal(code) => exit(code)
+        }
+    }
+}
+
+/// Accept incoming connections in a separate thread, so that the main thread
+/// can keep sending stdin to the clients that are already connected.
+fn spawn_acceptor(server: Server<'static>,
+                  options: Options,
+                  peers: Arc<Mutex<Vec<Peer>>>,
+                  events: ChannelSender<Event>,
+                  output: Output) {
+
+    thread::spawn(move || {
+        log!(3, "Acceptor thread spawned");
+        let mut next_id = 0;
+
+        for connection in server {
+            next_id += 1;
+
+            match connection {
+                Ok(connection) => spawn_handshake(connection, next_id,
+                                                  options.clone(), peers.clone(),
+                                                  events.clone(), output.clone()),
+                Err(err) => stderr!("Failed to accept connection: {}", err)
+            }
+        }
+    });
+}
+
+/// Perform the handshake with a newly connected client in its own thread,
+/// so that a client that never sends its upgrade request does not hold up
+/// the ones connecting after it. The client is added to `peers` once the
+/// handshake is done, before its frames are read, so that every event from
+/// its reader thread finds it there.
+fn spawn_handshake(connection: Connection<WebSocketStream, WebSocketStream>,
+                   id: u64,
+                   options: Options,
+                   peers: Arc<Mutex<Vec<Peer>>>,
+                   events: ChannelSender<Event>,
+                   output: Output) {
+
+    thread::spawn(move || {
+        log!(3, "Handshake thread spawned for client {}", id);
+
+        match accept_client(connection, id, &options, &output) {
+            Ok((peer, receiver)) => {
+                peers.lock().unwrap().push(peer);
+
+                ws::spawn_websocket_reader::<ReceiverObj>
+                    (receiver, output.clone(), None, id, events.clone());
+                events.send(Event::Connected).unwrap_or(());
+            },
+            Err(err) => stderr!("{}", err)
+        }
+    });
+}
+
+/// Perform the server side of the WebSocket handshake with a newly
+/// connected client, and send it the pre-provided messages.
+/// Errors are returned as a printable message.
+///
+/// # Returns
+/// Returns the client, along with the receiver to read its frames from.
+fn accept_client(connection: Connection<WebSocketStream, WebSocketStream>,
+                 id: u64,
+                 options: &Options,
+                 output: &Output) -> Result<(Peer, ReceiverObj), String> {
+
+    let (client, addr) = upgrade_client(connection, options.print_headers,
+                                        &options.headers)?;
+
+    let (mut sender, receiver) = client.split();
+
+    // Send pre-provided messages to every new client
+    if !options.messages.is_empty() {
+        if let Err(err) = send_messages(&mut sender, &options.messages,
+                                        output) {
+            return Err(format!("Failed to send messages to {}: {}",
+                               addr, err));
+        }
+    }
+
+    Ok((Peer {
+        id: id,
+        addr: addr,
+        sender: sender
+    }, receiver))
+}
+
+/// Read and answer the upgrade request of a newly connected client, adding
+/// `headers` to the response.
+///
+/// # Returns
+/// Returns the upgraded client along with the address it connected from.
+pub fn upgrade_client(connection: Connection<WebSocketStream, WebSocketStream>,
+                      print: bool,
+                      headers: &Vec<String>) -> Result<(WsClient, String), String> {
+
+    let request = match connection.read_request() {
+        Ok(request) => request,
+        Err(err) => {
+            log!(1, "Error object: {:?}", err);
+            return Err(format!("Failed to read upgrade request: {}", err));
+        }
+    };
+
+    let addr = match request.get_reader().peer_addr() {
+        Ok(addr) => format!("{}", addr),
+        Err(_) => String::from("unknown address")
+    };
+
+    if print {
+        print_headers("WebSocket upgrade request", &request.headers, None);
+    }
+
+    if let Err(err) = request.validate() {
+        log!(1, "Invalid request: {:?}", err);
+        if let Err(fail_err) = request.fail().send_into_inner() {
+            log!(1, "Failed to reject request: {:?}", fail_err);
+        }
+
+        return Err(format!("Rejected upgrade request from {}: {}", addr, err));
+    }
+
+    let mut response = request.accept();
+
+    // Add the headers passed from command line arguments
+    if !headers.is_empty() {
+        add_headers(&mut response.headers, headers);
+    }
+
+    if print {
+        print_headers("WebSocket upgrade response",
+                      &response.headers, Some(response.status));
+    }
+
+    let client = match response.send() {
+        Ok(client) => client,
+        Err(err) => {
+            log!(1, "Error object: {:?}", err);
+            return Err(format!("Failed to send upgrade response to {}: {}",
+                               addr, err));
+        }
+    };
+
+    stderr!("Client connected from {}", addr);
+
+    // permessage-deflate is never accepted from clients
+    let (sender, receiver) = client.split();
+    let client = Client::new(SenderObj::new(sender, None),
+                             ReceiverObj::new(receiver, None));
+
+    Ok((client, addr))
+}
+
+/// Answer the pings and close frames sent by the client with the given id,
+/// and forget the client once it has disconnected
+fn handle_event(peers: &mut Vec<Peer>,
+                id: u64,
+                event: ReaderEvent,
+                output: &Output) {
+
+    let index = match peers.iter().position(|peer| peer.id == id) {
+        Some(index) => index,
+        None => return
+    };
+
+    let connected = match event {
+        ReaderEvent::Ping(payload) => {
+            ws::send_pong(&mut peers[index].sender, payload, output).is_ok()
+        },
+        ReaderEvent::Pong(..) => {
+            log!(3, "Pong from {}", peers[index].addr);
+            true
+        },
+        ReaderEvent::Data(_) => true,
+        ReaderEvent::Close(code, _) => {
+            close::answer_close(&mut peers[index].sender, code, output);
+            false
+        },
+        ReaderEvent::Disconnected => false
+    };
+
+    if !connected {
+        let peer = peers.remove(index);
+        stderr!("Client {} disconnected", peer.addr);
+    }
+}
+
+/// Send the `pending` frames to all connected clients, and forget the
+/// clients that could not be sent to. The frames are left untouched until
+/// at least one client is connected.
+fn broadcast(peers: &mut Vec<Peer>,
+             pending: &mut Vec<FrameData>,
+             output: &Output) {
+
+    if peers.is_empty() {
+        return;
+    }
+
+    for frame in pending.drain(..) {
+
+        log!(4, "Read: {:?}", frame);
+        let message = ws::frame_to_message(frame);
+
+        let mut connected = Vec::new();
+        for mut peer in peers.drain(..) {
+            match peer.sender.send_message(&message) {
+                Ok(_) => connected.push(peer),
+                Err(err) => {
+                    log!(1, "Error object: {:?}", err);
+                    stderr!("Client {} disconnected: {}", peer.addr, err);
+                }
+            }
+        }
+
+        *peers = connected;
+
+        output.outgoing(&message);
+    }
+}
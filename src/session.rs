@@ -0,0 +1,617 @@
+//! A WebSocket connection made the same way the `wsta` command line tool
+//! makes it, so that other programs can use the same handshake.
+use std::io;
+use std::io::Write;
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use hyper::header::SetCookie;
+
+use websocket::{Client, Message, Receiver};
+use websocket::client::Sender as WsSender;
+use websocket::client::Receiver as WsReceiver;
+use websocket::client::request::{Request, Url};
+use websocket::message::Type;
+use websocket::stream::WebSocketStream;
+use websocket::dataframe::DataFrame;
+use websocket::header::{WebSocketProtocol, WebSocketExtensions};
+use websocket::result::WebSocketError;
+
+use cookies::CookieJar;
+use deflate;
+use deflate::{Deflate, Compressor, Decompressor};
+use deflate::Sender as SenderObj;
+use deflate::Receiver as ReceiverObj;
+use error::Error;
+use options::Options;
+use proxy::{Proxy, proxy_for, tcp_connect};
+use http::{log_in, run_login_steps, expand, print_headers, add_headers};
+use tls::Tls;
+use token::Token;
+use oauth;
+
+/// A connected WebSocket client
+pub type WsClient = Client<DataFrame, SenderObj, ReceiverObj>;
+
+/// An upgrade request that has not been sent yet
+type WsRequest = Request<WebSocketStream, WebSocketStream>;
+
+/// A connection to a WebSocket server
+pub struct Session {
+    client: WsClient,
+    protocol: Option<String>
+}
+
+impl Session {
+
+    /// Connect to `options.url`. If `options.login_url` is set, the session
+    /// cookie it returns is sent along with the upgrade request, as are
+    /// `options.headers` and `options.subprotocols`. With
+    /// `options.cookie_jar`, cookies are loaded from and saved to the jar,
+    /// and login is skipped while it holds cookies for the server. The
+    /// `options.login_steps` are run after that, and the values they extract
+    /// are filled into the URL and the headers. With
+    /// `options.oauth_token_url`, an OAuth2 access token is sent along with
+    /// the upgrade request.
+    /// permessage-deflate is offered unless `options.no_compression` is set.
+    /// The headers of the upgrade request and response are printed to stderr
+    /// when `options.print_headers` is set.
+    ///
+    /// Connecting gives up after `options.connect_timeout` seconds, and the
+    /// TLS and WebSocket handshakes together after
+    /// `options.handshake_timeout` seconds.
+    pub fn connect(options: &Options) -> Result<Session, Error> {
+        Session::connect_reusing(options, &mut None)
+    }
+
+    /// Connect like `connect`, logging in for a new token only if `token`
+    /// is missing or about to expire. Used when reconnecting with
+    /// `options.login_token` or `options.oauth_token_url`, so that the token
+    /// from the last login is reused while it is valid. The token is sent in
+    /// an `Authorization: Bearer` header, or as the query parameter
+    /// `options.login_token_param`.
+    pub fn connect_reusing(options: &Options,
+                           token: &mut Option<Token>) -> Result<Session, Error> {
+
+        let url = parse_url(&options.url)?;
+
+        // Load certificates before connecting, so that mistakes show early
+        let tls = if url.scheme() == "wss" {
+            let tls = Tls::from_options(options)?;
+            log!(3, "Created TLS settings: {:?}", tls);
+
+            Some(tls)
+        } else {
+            None
+        };
+
+        let mut jar = if options.cookie_jar.is_empty() {
+            CookieJar::new()
+        } else {
+            CookieJar::load(&options.cookie_jar)
+                .map_err(|err| Error::CookieJar(options.cookie_jar.clone(), err))?
+        };
+
+        // Authenticate if requested, unless the jar still has a cookie that
+        // an earlier login set for the server, or the token from an earlier
+        // login is still valid.
+        // This is done before connecting, so that a slow login does not
+        // count against the handshake timeout.
+        if !options.login_url.is_empty() {
+            if !options.login_token.is_empty() {
+                if token.as_ref().map_or(true, Token::needs_refresh) {
+                    *token = log_in(options, &mut jar)?;
+                    log!(2, "Got token: {:?}", token);
+
+                    save_cookies(options, &jar)?;
+                } else {
+                    log!(1, "Reusing the token from the last login");
+                }
+            } else if jar.has_login_cookie(&url) {
+                log!(1, "Using the cookies in {}, skipping login",
+                     options.cookie_jar);
+            } else {
+                log_in(options, &mut jar)?;
+                log!(2, "Got session cookie: {:?}", jar.header(&url));
+
+                save_cookies(options, &jar)?;
+            }
+        }
+
+        // Get an OAuth2 access token, unless the last one is still valid
+        if !options.oauth_token_url.is_empty() {
+            *token = Some(oauth::access_token(options, token.take(), &mut jar)?);
+            save_cookies(options, &jar)?;
+        }
+
+        // Run the multi-step login, and fill the values it extracted into
+        // the URL and headers of the upgrade request
+        let expanded;
+        let (options, mut url) = if options.login_steps.is_empty() {
+            (options, url)
+        } else {
+            let variables = run_login_steps(options, &mut jar)?;
+            save_cookies(options, &jar)?;
+
+            expanded = with_variables(options, &variables)?;
+            (&expanded, parse_url(&expanded.url)?)
+        };
+
+        // Send the token along with the upgrade request
+        let authorized;
+        let options = match *token {
+            Some(ref token) => {
+                authorized = with_token(options, token, &mut url);
+                &authorized
+            },
+            None => options
+        };
+
+        // Connect to the server, directly or through a proxy
+        log!(2, "About to connect to {}", url);
+        let connect_timeout = seconds(options.connect_timeout);
+        let stream = match proxy_for(options, &url)? {
+            Some(proxy) => connect_through(&proxy, &url, &options.url,
+                                           connect_timeout)?,
+            None => connect_directly(&url, &options.url, connect_timeout)?
+        };
+
+        let watchdog = match seconds(options.handshake_timeout) {
+            Some(timeout) => {
+                let watchdog = Watchdog::start(&stream, timeout)
+                    .map_err(|err| Error::Connect(options.url.clone(),
+                                                  WebSocketError::IoError(err)))?;
+                Some(watchdog)
+            },
+            None => None
+        };
+
+        let result = handshake(stream, &url, options, tls, &mut jar);
+
+        // If the watchdog shut the connection down, that is what made the
+        // handshake fail, and the connection is of no use even if it did not
+        if watchdog.map_or(false, Watchdog::stop) {
+            log!(1, "Handshake result before timing out: {:?}",
+                 result.as_ref().err());
+            return Err(Error::HandshakeTimeout(options.url.clone(),
+                                               options.handshake_timeout));
+        }
+
+        let session = result?;
+        save_cookies(options, &jar)?;
+
+        Ok(session)
+    }
+
+    /// The subprotocol selected by the server, if any
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().map(|protocol| &protocol[..])
+    }
+
+    /// Send a single message to the server
+    pub fn send(&mut self, message: &Message) -> Result<(), Error> {
+        log!(4, "Sending: {:?}", message);
+        self.client.send_message(message).map_err(Error::Send)
+    }
+
+    /// Send a text frame to the server
+    pub fn send_text(&mut self, text: &str) -> Result<(), Error> {
+        self.send(&Message::text(text))
+    }
+
+    /// Send a binary frame to the server
+    pub fn send_binary(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        self.send(&Message::binary(data))
+    }
+
+    /// Wait for the next text or binary frame from the server. Pings are
+    /// answered and pongs are skipped while waiting. When the server closes
+    /// the connection, its close frame is answered and `Error::Closed` is
+    /// returned.
+    pub fn receive(&mut self) -> Result<Message<'static>, Error> {
+        loop {
+            let message: Message<'static> = self.client.get_mut_receiver()
+                .recv_message()?;
+            log!(4, "Received: {:?}", message);
+
+            match message.opcode {
+                Type::Text | Type::Binary => return Ok(message),
+                Type::Ping => {
+                    let payload = message.payload.into_owned();
+                    self.send(&Message::pong(payload))?;
+                },
+                Type::Pong => log!(3, "Skipping pong: {:?}", message),
+                Type::Close => {
+                    let code = message.cd_status_code;
+                    let reason = String::from_utf8_lossy(&message.payload)
+                        .into_owned();
+
+                    let reply = match code {
+                        Some(code) => Message::close_because(code, ""),
+                        None => Message::close()
+                    };
+
+                    // The server may already have dropped the connection
+                    self.send(&reply).unwrap_or(());
+                    return Err(Error::Closed(code, reason));
+                }
+            }
+        }
+    }
+
+    /// Close the connection with `code` and `reason`, and wait for the
+    /// server to answer with a close frame of its own. Frames received in
+    /// the meantime are dropped.
+    ///
+    /// # Returns
+    /// Returns the close code sent by the server.
+    pub fn close(mut self, code: u16, reason: &str) -> Result<Option<u16>, Error> {
+        log!(2, "Closing connection with code {}", code);
+        self.send(&Message::close_because(code, reason))?;
+
+        loop {
+            let message: Message = self.client.get_mut_receiver()
+                .recv_message()?;
+
+            if message.opcode == Type::Close {
+                self.client.get_mut_sender().shutdown_all().unwrap_or(());
+                return Ok(message.cd_status_code);
+            }
+
+            log!(3, "Dropping frame while closing: {:?}", message);
+        }
+    }
+
+    /// Split the session into a sender and a receiver, so that frames can
+    /// be sent and received from different threads
+    pub fn split(self) -> (SenderObj, ReceiverObj) {
+        self.client.split()
+    }
+}
+
+/// Perform the TLS and WebSocket handshakes over `stream`, sending the
+/// cookies in `jar` and the headers given in `options` along with the
+/// upgrade request. Cookies set by the response are stored in `jar`.
+fn handshake(stream: TcpStream,
+             url: &Url,
+             options: &Options,
+             tls: Option<Tls>,
+             jar: &mut CookieJar) -> Result<Session, Error> {
+
+    let mut request = build_request(stream, url, &options.url, tls)?;
+
+    // Set Origin header to be equal to the websocket url
+    let origin = get_origin(url);
+    log!(3, "Parsed Origin string: {}", origin);
+    request.headers.set_raw("Origin", vec![origin.into_bytes()]);
+
+    if let Some(session_cookie) = jar.header(url) {
+        request.headers.set(session_cookie);
+        log!(3, "Session cookie set on request. Headers are now: {:?}",
+             request.headers);
+    }
+
+    // Offer subprotocols. A raw header from -H takes precedence.
+    if !options.subprotocols.is_empty() {
+        request.headers.set(WebSocketProtocol(options.subprotocols.clone()));
+    }
+
+    // Offer compression. A raw header from -H takes precedence here too.
+    if !options.no_compression {
+        request.headers.set(WebSocketExtensions(vec![Deflate::offer()]));
+    }
+
+    // Add the headers passed from command line arguments
+    if !options.headers.is_empty() {
+        add_headers(&mut request.headers, &options.headers);
+    }
+
+    // Print request
+    if options.print_headers {
+        print_headers("WebSocket upgrade request", &request.headers, None);
+    }
+
+    // Send the request
+    log!(3, "About to send and unwrap request");
+    let response = match request.send() {
+        Ok(response) => {
+            log!(3, "Request sent");
+
+            response
+        },
+        Err(err) => {
+            log!(1, "Error object: {:?}", err);
+            return Err(Error::Handshake(err));
+        }
+    };
+
+    // Dump headers when requested
+    if options.print_headers {
+        print_headers("WebSocket upgrade response",
+                      &response.headers, Some(response.status));
+    }
+
+    // Ensure the response is valid
+    if let Err(err) = response.validate() {
+        log!(1, "Invalid reponse: {:?}", err);
+        return Err(Error::Handshake(err));
+    }
+
+    if let Some(set_cookie) = response.headers.get::<SetCookie>() {
+        jar.store(url, set_cookie);
+    }
+
+    let protocol = check_subprotocol(&options.subprotocols,
+                                     response.protocol())?;
+    log!(2, "Negotiated subprotocol: {:?}", protocol);
+
+    let offered = response.get_request().headers.get::<WebSocketExtensions>();
+    let deflate = deflate::negotiate(offered, response.extensions())
+        .map_err(Error::Handshake)?;
+    log!(2, "Negotiated permessage-deflate: {:?}", deflate);
+
+    // Get a Client that compresses frames if the server agreed to
+    let (reader, writer) = response.into_inner();
+    let sender = SenderObj::new(WsSender::new(writer, true),
+                                deflate.as_ref().map(Compressor::new));
+    let receiver = ReceiverObj::new(WsReceiver::new(reader, false),
+                                    deflate.as_ref().map(Decompressor::new));
+    let client = Client::new(sender, receiver);
+    log!(3, "Client created");
+
+    Ok(Session {
+        client: client,
+        protocol: protocol
+    })
+}
+
+/// A copy of `options` with the values extracted by the login steps filled
+/// into the URL and the headers
+fn with_variables(options: &Options,
+                  variables: &HashMap<String, String>) -> Result<Options, Error> {
+
+    let mut expanded = options.clone();
+
+    expanded.url = expand(&options.url, variables, true)
+        .map_err(Error::UnknownVariable)?;
+    expanded.headers = options.headers.iter()
+        .map(|header| expand(header, variables, false))
+        .collect::<Result<Vec<String>, String>>()
+        .map_err(Error::UnknownVariable)?;
+
+    Ok(expanded)
+}
+
+/// A copy of `options` with `token` in an `Authorization: Bearer` header,
+/// or added to `url` as the query parameter `options.login_token_param`.
+/// A header given with -H takes precedence.
+fn with_token(options: &Options, token: &Token, url: &mut Url) -> Options {
+    let mut authorized = options.clone();
+
+    if options.login_token_param.is_empty() {
+        authorized.headers.insert(0, format!("Authorization: Bearer {}",
+                                             token.value));
+    } else {
+        url.query_pairs_mut().append_pair(&options.login_token_param,
+                                          &token.value);
+    }
+
+    authorized
+}
+
+/// Save `jar` to `options.cookie_jar`, if it is set
+fn save_cookies(options: &Options, jar: &CookieJar) -> Result<(), Error> {
+    if options.cookie_jar.is_empty() {
+        return Ok(());
+    }
+
+    log!(3, "Saving cookies to {}", options.cookie_jar);
+    jar.save(&options.cookie_jar)
+        .map_err(|err| Error::CookieJar(options.cookie_jar.clone(), err))
+}
+
+/// Parse a WebSocket URL
+pub fn parse_url(raw_url: &str) -> Result<Url, Error> {
+
+    log!(2, "About to parse: {}", raw_url);
+    let url = match Url::parse(raw_url) {
+        Ok(res) => res,
+        Err(err) => {
+            log!(1, "Error object: {:?}", err);
+            return Err(Error::InvalidUrl(String::from(raw_url), err));
+        }
+    };
+    log!(3, "Parsed URL: {:?}", url);
+
+    Ok(url)
+}
+
+/// Open a connection to the server at `url`, giving up after `timeout`.
+/// `raw_url` is the URL as it was given, for error messages.
+fn connect_directly(url: &Url, raw_url: &str, timeout: Option<Duration>)
+                    -> Result<TcpStream, Error> {
+
+    // Resolve the host first, so that an unknown host can be told apart from
+    // a server that is down
+    let host = url.host_str().unwrap_or("");
+    let port = url.port_or_known_default().unwrap_or(80);
+    if let Err(err) = (host, port).to_socket_addrs() {
+        log!(1, "Error object: {:?}", err);
+        return Err(Error::Dns(String::from(host), err));
+    }
+
+    match tcp_connect(host, port, timeout) {
+        Ok(stream) => Ok(stream),
+        Err(ref err) if timeout.is_some() && is_timeout(err) => {
+            log!(1, "Error: {:?}", err);
+            Err(Error::ConnectTimeout(String::from(raw_url), as_secs(timeout)))
+        },
+        Err(err) => {
+            log!(1, "Error: {:?}", err);
+            Err(Error::Connect(String::from(raw_url),
+                               WebSocketError::IoError(err)))
+        }
+    }
+}
+
+/// Open a connection to the server at `url` through a tunnel opened by
+/// `proxy`, giving up after `timeout`. The host name of the server is
+/// resolved by the proxy.
+fn connect_through(proxy: &Proxy, url: &Url, raw_url: &str,
+                   timeout: Option<Duration>) -> Result<TcpStream, Error> {
+
+    let host = url.host_str().unwrap_or("");
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    match proxy.connect(host, port, timeout) {
+        Ok(tunnel) => Ok(tunnel),
+        Err(ref err) if timeout.is_some() && is_timeout(err) => {
+            log!(1, "Error object: {:?}", err);
+            Err(Error::ConnectTimeout(String::from(raw_url), as_secs(timeout)))
+        },
+        Err(err) => {
+            log!(1, "Error object: {:?}", err);
+            Err(Error::Proxy(format!("{}", proxy), err))
+        }
+    }
+}
+
+/// Wrap `stream` with `tls` if given, and build the upgrade request. `raw_url`
+/// is the URL as it was given, for error messages.
+fn build_request(stream: TcpStream, url: &Url, raw_url: &str, tls: Option<Tls>)
+                 -> Result<WsRequest, Error> {
+
+    let stream = if let Some(tls) = tls {
+        match tls.connect(stream, url.host_str().unwrap_or("")) {
+            Ok(stream) => WebSocketStream::Ssl(stream),
+            Err(err) => {
+                log!(1, "Error object: {:?}", err);
+                return Err(Error::Tls(String::from(raw_url),
+                                      WebSocketError::IoError(err)));
+            }
+        }
+    } else {
+        WebSocketStream::Tcp(stream)
+    };
+
+    let result = stream.try_clone()
+        .map_err(WebSocketError::IoError)
+        .and_then(|reader| Request::new(url, reader, stream));
+
+    result.map_err(|err| Error::Connect(String::from(raw_url), err))
+}
+
+/// Check the subprotocol selected by the server against the ones offered.
+/// When none were offered with `--subprotocol`, anything goes, as one may
+/// have been offered with a raw header.
+fn check_subprotocol(offered: &[String], selected: Option<&WebSocketProtocol>)
+                     -> Result<Option<String>, Error> {
+    let selected: Vec<String> = selected.map(|protocol| protocol.0.clone())
+        .unwrap_or_default();
+
+    if offered.is_empty() {
+        return Ok(selected.into_iter().next());
+    }
+
+    if selected.len() == 1 && offered.contains(&selected[0]) {
+        Ok(selected.into_iter().next())
+    } else {
+        Err(Error::Subprotocol(offered.to_vec(), selected))
+    }
+}
+
+/// A timeout given in seconds, where 0 means no timeout
+pub fn seconds(timeout: u64) -> Option<Duration> {
+    match timeout {
+        0 => None,
+        timeout => Some(Duration::from_secs(timeout))
+    }
+}
+
+fn as_secs(timeout: Option<Duration>) -> u64 {
+    timeout.map_or(0, |timeout| timeout.as_secs())
+}
+
+/// Whether `err` comes from a read, write or connect timing out. Timed out
+/// reads and writes fail with `WouldBlock` on some platforms.
+fn is_timeout(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => true,
+        _ => false
+    }
+}
+
+/// Shuts a connection down once a timeout has passed, unless stopped
+/// before that. Blocking reads and writes on the connection then fail, so
+/// that a handshake that has stalled anywhere is given up on.
+struct Watchdog {
+    stop: ChannelSender<()>,
+    fired: Arc<AtomicBool>
+}
+
+impl Watchdog {
+    fn start(stream: &TcpStream, timeout: Duration) -> io::Result<Watchdog> {
+        let stream = stream.try_clone()?;
+        let (stop, stopped) = channel();
+        let fired = Arc::new(AtomicBool::new(false));
+        let watchdog_fired = fired.clone();
+
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(timeout) {
+                log!(2, "Handshake timed out, shutting down the connection");
+                watchdog_fired.store(true, Ordering::SeqCst);
+                stream.shutdown(Shutdown::Both).unwrap_or(());
+            }
+        });
+
+        Ok(Watchdog {
+            stop: stop,
+            fired: fired
+        })
+    }
+
+    /// Stop the watchdog, returning whether it shut the connection down
+    fn stop(self) -> bool {
+        self.stop.send(()).unwrap_or(());
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+/// Parses an Origin string from a websocket URL, replacing ws[s] with http[s].
+fn get_origin(url: &Url) -> String {
+    let scheme = if url.scheme() == "wss" {
+        "https"
+    } else {
+        "http"
+    };
+
+    format!("{}://{}", scheme, url.host_str().unwrap_or(""))
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn subprotocol_must_be_one_offered() {
+    let offered = vec![String::from("v2.feed"), String::from("v1.feed")];
+    let selected = |name: &str| WebSocketProtocol(vec![String::from(name)]);
+
+    assert_eq!(check_subprotocol(&offered, Some(&selected("v1.feed"))).unwrap(),
+               Some(String::from("v1.feed")));
+    assert!(check_subprotocol(&offered, Some(&selected("chat"))).is_err());
+    assert!(check_subprotocol(&offered, None).is_err());
+    assert_eq!(check_subprotocol(&[], None).unwrap(), None);
+}
+
+#[test]
+fn origin_follows_the_scheme() {
+    let url = Url::parse("wss://example.com:8443/feed").unwrap();
+    assert_eq!(get_origin(&url), "https://example.com");
+
+    let url = Url::parse("ws://example.com/feed").unwrap();
+    assert_eq!(get_origin(&url), "http://example.com");
+}
@@ -0,0 +1,607 @@
+//! TLS settings shared by wss connections and https logins.
+//!
+//! Certificates are verified against the system's CA certificates, or the
+//! ones in `--cacert`, and must match the host name of the server. A client
+//! certificate is presented when `--cert` is given, either as PEM along with
+//! `--key`, or as a PKCS#12 bundle ending in `.p12` or `.pfx`.
+//!
+//! With `--pin`, the handshake only succeeds if a certificate in the chain
+//! has a public key or fingerprint that was pinned.
+//!
+//! With `-I`, the negotiated session and the certificate chain of the server
+//! are printed after every handshake, along with the reason a certificate
+//! was rejected.
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::ffi::CString;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::raw::{c_int, c_ulong};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use hyper;
+use hyper::net::{NetworkStream, SslClient};
+
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslStream, SSL_VERIFY_NONE,
+                   SSL_VERIFY_PEER, SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3,
+                   SSL_OP_NO_COMPRESSION};
+use openssl::ssl::error::SslError;
+use openssl::x509::{X509, X509FileType, X509StoreContext, X509ValidationError};
+use openssl::crypto::hash;
+use openssl::crypto::pkey::{PKey, Parts};
+use openssl_sys as ffi;
+use openssl_verify::verify_callback;
+
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+
+use error::Error;
+use options::Options;
+
+// The cipher list used by curl, as in hyper's OpensslClient
+const CIPHERS: &'static str = "ALL!EXPORT!EXPORT40!EXPORT56!aNULL!LOW!RC4@STRENGTH";
+
+// Both the upgrade request and the login request are HTTP/1.1
+const ALPN_PROTOCOLS: &'static [&'static [u8]] = &[b"http/1.1"];
+
+// X509_FLAG_NO_* flags, to print only the subject, issuer and validity
+const PRINT_NAMES_AND_VALIDITY: c_ulong = 0x1 | 0x2 | 0x4 | 0x8 | 0x80 | 0x100 |
+                                          0x200 | 0x400 | 0x800 | 0x1000;
+
+extern "C" {
+    fn i2d_PUBKEY(key: *mut ffi::EVP_PKEY, out: *mut *mut u8) -> c_int;
+    fn X509_print_ex(bio: *mut ffi::BIO, x509: *mut ffi::X509, name_flags: c_ulong,
+                     flags: c_ulong) -> c_int;
+}
+
+/// The TLS settings to connect with
+#[derive(Clone)]
+pub struct Tls {
+    context: Arc<SslContext>,
+    verify: bool,
+
+    /// Print the session and certificate chain after every handshake
+    print: bool,
+
+    /// A certificate in the chain must match one of these, if there are any
+    pins: Vec<Pin>
+}
+
+/// A pinned certificate
+#[derive(Debug, Clone, PartialEq)]
+enum Pin {
+
+    /// The base64 SHA-256 hash of a public key, given as `sha256//BASE64`
+    PublicKey(String),
+
+    /// The SHA-256 fingerprint of a certificate in hex, as printed by `-I`
+    Certificate(String)
+}
+
+impl Pin {
+
+    /// Parse a pin given as `sha256//BASE64`, or as a SHA-256 fingerprint
+    /// in hex with or without colons
+    fn parse(raw_pin: &str) -> Option<Pin> {
+        let raw_pin = raw_pin.trim();
+
+        if raw_pin.starts_with("sha256//") {
+            let hash = &raw_pin["sha256//".len()..];
+            return match hash.from_base64() {
+                Ok(ref bytes) if bytes.len() == 32 => {
+                    Some(Pin::PublicKey(String::from(hash)))
+                },
+                _ => None
+            };
+        }
+
+        let hex: String = raw_pin.chars().filter(|c| *c != ':').collect();
+        if hex.len() == 64 && hex.chars().all(|c| c.is_digit(16)) {
+            Some(Pin::Certificate(hex.to_uppercase()))
+        } else {
+            None
+        }
+    }
+
+    fn matches(&self, cert: &Certificate) -> bool {
+        match *self {
+            Pin::PublicKey(ref hash) => *hash == cert.public_key,
+            Pin::Certificate(ref hex) => *hex == cert.fingerprint.replace(":", "")
+        }
+    }
+}
+
+/// A certificate presented by the server, as seen while verifying it
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Certificate {
+
+    /// The position in the chain, where 0 is the certificate of the server
+    depth: u32,
+    subject: String,
+    issuer: String,
+    not_before: String,
+    not_after: String,
+
+    /// The DNS names and IP addresses in the subjectAltName extension
+    names: Vec<String>,
+    fingerprint: String,
+
+    /// The base64 SHA-256 hash of the public key, as used by `--pin`
+    public_key: String,
+
+    /// Why the certificate was rejected, if it was
+    error: Option<String>
+}
+
+impl Tls {
+
+    /// Build the TLS settings from `options.cacert`, `options.cert`,
+    /// `options.key`, `options.insecure` and `options.pins`
+    pub fn from_options(options: &Options) -> Result<Tls, Error> {
+        let mut pins = Vec::new();
+        for raw_pin in &options.pins {
+            match Pin::parse(raw_pin) {
+                Some(pin) => pins.push(pin),
+                None => return Err(Error::InvalidPin(raw_pin.clone()))
+            }
+        }
+
+        let mut context = SslContext::new(SslMethod::Sslv23)
+            .map_err(|err| certificate_error("", err))?;
+
+        context.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3 |
+                            SSL_OP_NO_COMPRESSION);
+        context.set_cipher_list(CIPHERS)
+            .map_err(|err| certificate_error("", err))?;
+        context.set_alpn_protocols(ALPN_PROTOCOLS);
+
+        if options.insecure {
+            log!(2, "Not verifying certificates");
+            context.set_verify(SSL_VERIFY_NONE, None);
+        }
+
+        if !options.cacert.is_empty() {
+            log!(2, "Loading CA certificates from {}", options.cacert);
+            context.set_CA_file(&options.cacert)
+                .map_err(|err| certificate_error(&options.cacert, err))?;
+        } else {
+            context.set_default_verify_paths()
+                .map_err(|err| certificate_error("", err))?;
+        }
+
+        if !options.cert.is_empty() {
+            log!(2, "Loading client certificate from {}", options.cert);
+            let result = if is_pkcs12(&options.cert) {
+                load_pkcs12(&mut context, &options.cert, &options.cert_password)
+            } else {
+                load_pem(&mut context, &options.cert, &options.key)
+            };
+
+            result.map_err(|err| certificate_error(&options.cert, err))?;
+        }
+
+        Ok(Tls {
+            context: Arc::new(context),
+            verify: !options.insecure,
+            print: options.print_headers,
+            pins: pins
+        })
+    }
+
+    /// Perform the TLS handshake with `host` over `stream`. The host name is
+    /// sent with SNI, and checked against the certificate of the server
+    /// unless verification is turned off.
+    pub fn connect<S: Read + Write>(&self, stream: S, host: &str)
+                                    -> io::Result<SslStream<S>> {
+        let mut ssl = Ssl::new(&self.context).map_err(into_io_error)?;
+        ssl.set_hostname(host).map_err(into_io_error)?;
+
+        // The callback is set even when not verifying, to see the chain
+        let chain = Arc::new(Mutex::new(Vec::new()));
+        let seen = chain.clone();
+        let verify = self.verify;
+        let keep = self.print || !self.pins.is_empty();
+        let name = host.to_owned();
+        let mode = if verify { SSL_VERIFY_PEER } else { SSL_VERIFY_NONE };
+
+        ssl.set_verify_callback(mode, move |ok, context| {
+            let verified = verify_callback(&name, ok, context);
+
+            if keep {
+                let error = if verified {
+                    None
+                } else if ok {
+                    Some(format!("does not match host name '{}'", name))
+                } else {
+                    context.get_error().map(describe_validation_error)
+                };
+
+                record(&mut seen.lock().unwrap(), context, error);
+            }
+
+            verified || !verify
+        });
+
+        let result = SslStream::connect(ssl, stream).map_err(into_io_error);
+        let chain = chain.lock().unwrap();
+
+        if self.print {
+            print_session(host, result.as_ref().ok().map(|stream| stream.ssl()),
+                          &chain);
+        }
+
+        if result.is_ok() && !self.pins.is_empty() {
+            let pinned = chain.iter()
+                .any(|cert| self.pins.iter().any(|pin| pin.matches(cert)));
+
+            if !pinned {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          format!("No certificate of '{}' \
+                                                   matches --pin", host)));
+            }
+        }
+
+        result
+    }
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tls {{ verify: {}, pins: {:?} }}", self.verify, self.pins)
+    }
+}
+
+impl<T: NetworkStream + Send + Clone> SslClient<T> for Tls {
+    type Stream = SslStream<T>;
+
+    fn wrap_client(&self, stream: T, host: &str) -> hyper::Result<Self::Stream> {
+        self.connect(stream, host).map_err(From::from)
+    }
+}
+
+/// Add the certificate being verified to `chain`. The callback runs once
+/// for every error found, so a certificate may be seen more than once.
+fn record(chain: &mut Vec<Certificate>, context: &X509StoreContext,
+          error: Option<String>) {
+    let depth = context.error_depth();
+
+    if let Some(seen) = chain.iter_mut().find(|cert| cert.depth == depth) {
+        seen.error = seen.error.take().or(error);
+        return;
+    }
+
+    if let Some(x509) = context.get_current_cert() {
+        let mut cert = describe_certificate(&x509);
+        cert.depth = depth;
+        cert.error = error;
+        chain.push(cert);
+    }
+}
+
+/// Why a certificate was rejected, for the most common reasons
+fn describe_validation_error(err: X509ValidationError) -> String {
+    use openssl::x509::X509ValidationError::*;
+
+    let reason = match err {
+        X509CertHasExpired => "the certificate has expired",
+        X509CertNotYetValid => "the certificate is not yet valid",
+        X509DepthZeroSelfSignedCert => "the certificate is self-signed",
+        X509SelfSignedCertInChain => "a self-signed certificate is in the chain",
+        X509UnableToGetIssuerCert |
+        X509UnableToGetIssuerCertLocally |
+        X509UnableToVerifyLeafSignature => "the issuer is not a trusted CA",
+        X509CertUntrusted | X509CertRejected => "the certificate is not trusted",
+        X509CertRevoked => "the certificate has been revoked",
+        X509InvalidCA => "the issuer is not a valid CA",
+        X509CertChainTooLong => "the chain is too long",
+        X509CertSignatureFailure => "the signature is invalid",
+        X509InvalidPurpose => "the certificate is not meant for servers",
+        X509UnknownError(code) => return format!("verification error {}", code),
+        _ => "the certificate could not be verified"
+    };
+
+    String::from(reason)
+}
+
+/// Collect the details of `x509` to print
+fn describe_certificate(x509: &X509) -> Certificate {
+    let mut cert = parse_certificate_text(&certificate_text(x509));
+
+    if let Some(names) = x509.subject_alt_names() {
+        for name in names.iter() {
+            if let Some(dns) = name.dnsname() {
+                cert.names.push(String::from(dns));
+            } else if let Some(ip) = name.ipaddress() {
+                cert.names.push(format_ip(ip));
+            }
+        }
+    }
+
+    if let Some(fingerprint) = x509.fingerprint(hash::Type::SHA256) {
+        let hex: Vec<String> = fingerprint.iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        cert.fingerprint = hex.join(":");
+    }
+
+    cert.public_key = public_key_hash(x509).unwrap_or_default();
+
+    cert
+}
+
+/// The base64 SHA-256 hash of the DER encoded public key of `x509`, as in
+/// HPKP and curl's --pinnedpubkey
+fn public_key_hash(x509: &X509) -> Option<String> {
+    unsafe {
+        let key = ffi::X509_get_pubkey(x509.get_handle());
+        if key.is_null() {
+            return None;
+        }
+
+        let len = i2d_PUBKEY(key, ptr::null_mut());
+        let mut der = vec![0u8; len.max(0) as usize];
+        let mut out = der.as_mut_ptr();
+        let written = i2d_PUBKEY(key, &mut out);
+        ffi::EVP_PKEY_free(key);
+
+        if written <= 0 {
+            return None;
+        }
+
+        der.truncate(written as usize);
+        Some(hash::hash(hash::Type::SHA256, &der).to_base64(STANDARD))
+    }
+}
+
+/// The subject, issuer and validity of `x509`, as printed by OpenSSL
+fn certificate_text(x509: &X509) -> String {
+    let mut text = Vec::new();
+
+    unsafe {
+        let bio = ffi::BIO_new(ffi::BIO_s_mem());
+        if bio.is_null() {
+            return String::new();
+        }
+
+        X509_print_ex(bio, x509.get_handle(), 0, PRINT_NAMES_AND_VALIDITY);
+
+        let mut buf = [0u8; 1024];
+        loop {
+            let len = ffi::BIO_read(bio, buf.as_mut_ptr() as *mut _,
+                                    buf.len() as c_int);
+            if len <= 0 {
+                break;
+            }
+            text.extend_from_slice(&buf[..len as usize]);
+        }
+
+        ffi::BIO_free_all(bio);
+    }
+
+    String::from_utf8_lossy(&text).into_owned()
+}
+
+/// Pick the fields out of the text printed by `certificate_text`
+fn parse_certificate_text(text: &str) -> Certificate {
+    let mut cert = Certificate::default();
+
+    for line in text.lines() {
+        let line = line.trim();
+        let (field, value) = match line.find(':') {
+            Some(colon) => (line[..colon].trim(), line[colon + 1..].trim()),
+            None => continue
+        };
+
+        let value = String::from(value);
+        match field {
+            "Subject" => cert.subject = value,
+            "Issuer" => cert.issuer = value,
+            "Not Before" => cert.not_before = value,
+            "Not After" => cert.not_after = value,
+            _ => {}
+        }
+    }
+
+    cert
+}
+
+/// An IP address from a subjectAltName, which holds it as raw bytes
+fn format_ip(ip: &[u8]) -> String {
+    if ip.len() == 4 {
+        format!("{}", Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+    } else if ip.len() == 16 {
+        let mut segments = [0u16; 8];
+        for (i, segment) in segments.iter_mut().enumerate() {
+            *segment = (ip[i * 2] as u16) << 8 | ip[i * 2 + 1] as u16;
+        }
+        format!("{}", Ipv6Addr::from(segments))
+    } else {
+        String::from("(invalid IP address)")
+    }
+}
+
+/// Print the negotiated session, if the handshake succeeded, and the
+/// certificates of the server to stderr, in the style of `print_headers`
+fn print_session(host: &str, ssl: Option<&Ssl>, chain: &[Certificate]) {
+    stderr!("TLS session with {}", host);
+    stderr!("---");
+
+    match ssl {
+        Some(ssl) => {
+            stderr!("Protocol: {}", ssl.version());
+
+            if let Some(cipher) = ssl.get_current_cipher() {
+                stderr!("Cipher: {} ({} bits)", cipher.name(), cipher.bits().secret);
+            }
+
+            stderr!("SNI: {}", host);
+
+            match ssl.selected_alpn_protocol() {
+                Some(protocol) => {
+                    stderr!("ALPN: {}", String::from_utf8_lossy(protocol))
+                },
+                None => stderr!("ALPN: none")
+            }
+        },
+        None => stderr!("The handshake failed")
+    }
+
+    // The certificate of the server comes first
+    let mut chain = chain.to_vec();
+    chain.sort_by_key(|cert| cert.depth);
+
+    for cert in chain {
+        stderr!("Certificate {}", cert.depth);
+        stderr!("  Subject: {}", cert.subject);
+        stderr!("  Issuer: {}", cert.issuer);
+        stderr!("  Valid: {} to {}", cert.not_before, cert.not_after);
+
+        if !cert.names.is_empty() {
+            stderr!("  Names: {}", cert.names.join(", "));
+        }
+
+        stderr!("  SHA-256: {}", cert.fingerprint);
+        stderr!("  Public key: sha256//{}", cert.public_key);
+
+        if let Some(error) = cert.error {
+            stderr!("  Rejected: {}", error);
+        }
+    }
+
+    stderr!("");
+}
+
+/// OpenSSL errors as io::Errors, which both WebSocket and hyper errors hold
+fn into_io_error(err: SslError) -> io::Error {
+    match err {
+        SslError::StreamError(err) => err,
+        err => io::Error::new(io::ErrorKind::Other, err)
+    }
+}
+
+/// Keep the underlying error of a file that could not be read, so that the
+/// reason is described
+fn certificate_error(path: &str, err: SslError) -> Error {
+    Error::Certificate(String::from(path), into_io_error(err))
+}
+
+/// PKCS#12 bundles are told apart from PEM files by their extension
+fn is_pkcs12(path: &str) -> bool {
+    let path = path.to_lowercase();
+    path.ends_with(".p12") || path.ends_with(".pfx")
+}
+
+/// Load a PEM certificate chain, and its key from `key`. Without a key file,
+/// the key is expected to be in the certificate file.
+fn load_pem(context: &mut SslContext, cert: &str, key: &str) -> Result<(), SslError> {
+    let key = if key.is_empty() { cert } else { key };
+
+    // OpenSSL does not tell a missing file apart from an invalid one
+    File::open(cert).and_then(|_| File::open(key))
+        .map_err(SslError::StreamError)?;
+
+    context.set_certificate_chain_file(cert, X509FileType::PEM)?;
+    context.set_private_key_file(key, X509FileType::PEM)?;
+    context.check_private_key()
+}
+
+/// Load the certificate, key and CA certificates of a PKCS#12 bundle
+fn load_pkcs12(context: &mut SslContext, path: &str, password: &str)
+               -> Result<(), SslError> {
+    let mut der = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut der))
+        .map_err(SslError::StreamError)?;
+
+    let password = CString::new(password).map_err(|err| {
+        SslError::StreamError(io::Error::new(io::ErrorKind::InvalidInput, err))
+    })?;
+
+    unsafe {
+        let mut data = der.as_ptr();
+        let p12 = ffi::d2i_PKCS12(ptr::null_mut(), &mut data, der.len() as _);
+        if p12.is_null() {
+            return Err(SslError::get());
+        }
+
+        let mut pkey = ptr::null_mut();
+        let mut cert = ptr::null_mut();
+        let mut chain = ptr::null_mut();
+        let parsed = ffi::PKCS12_parse(p12, password.as_ptr(), &mut pkey,
+                                       &mut cert, &mut chain);
+        ffi::PKCS12_free(p12);
+
+        if parsed != 1 || pkey.is_null() || cert.is_null() {
+            return Err(SslError::get());
+        }
+
+        // Both are reference counted, so they can be freed once in use
+        let pkey = PKey::from_handle(pkey, Parts::Both);
+        let cert = X509::new(cert, true);
+        context.set_certificate(&cert)?;
+        context.set_private_key(&pkey)?;
+
+        if !chain.is_null() {
+            let stack = chain as *mut ffi::_STACK;
+            loop {
+                let ca = ffi::sk_pop(stack) as *mut ffi::X509;
+                if ca.is_null() {
+                    break;
+                }
+
+                // The context takes ownership of the extra certificates
+                context.add_extra_chain_cert(&X509::new(ca, false))?;
+            }
+            ffi::sk_free(stack);
+        }
+
+        context.check_private_key()
+    }
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn pins_are_parsed() {
+    let hash = "r/mIkG3eEpVdm+u/ko/cwxzOMo1bk4TyHIlByibiA5E=";
+    assert_eq!(Pin::parse(&format!("sha256//{}", hash)),
+               Some(Pin::PublicKey(String::from(hash))));
+
+    let hex = "ab:cd:ef:01:23:45:67:89:ab:cd:ef:01:23:45:67:89:\
+               ab:cd:ef:01:23:45:67:89:ab:cd:ef:01:23:45:67:89";
+    let cert = Certificate {
+        fingerprint: hex.to_uppercase(),
+        ..Certificate::default()
+    };
+    assert!(Pin::parse(hex).unwrap().matches(&cert));
+
+    assert_eq!(Pin::parse("sha256//tooshort"), None);
+    assert_eq!(Pin::parse("md5//r/mIkG3eEpVdm+u/ko/cwxzOMo1bk4TyHIlByibiA5E="), None);
+}
+
+#[test]
+fn certificate_text_is_parsed() {
+    let text = "        Issuer: C=US, O=Let's Encrypt, CN=R3
+        Validity
+            Not Before: Jan  1 00:00:00 2024 GMT
+            Not After : Mar 31 23:59:59 2024 GMT
+        Subject: CN=example.com
+";
+
+    let cert = parse_certificate_text(text);
+    assert_eq!(cert.issuer, "C=US, O=Let's Encrypt, CN=R3");
+    assert_eq!(cert.subject, "CN=example.com");
+    assert_eq!(cert.not_before, "Jan  1 00:00:00 2024 GMT");
+    assert_eq!(cert.not_after, "Mar 31 23:59:59 2024 GMT");
+
+    assert_eq!(format_ip(&[127, 0, 0, 1]), "127.0.0.1");
+}
+
+#[test]
+fn pkcs12_is_detected_by_extension() {
+    assert!(is_pkcs12("client.p12"));
+    assert!(is_pkcs12("/etc/ssl/CLIENT.PFX"));
+    assert!(!is_pkcs12("client.pem"));
+    assert!(!is_pkcs12("client.crt"));
+}
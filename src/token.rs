@@ -0,0 +1,110 @@
+//! Bearer tokens returned in the JSON body of the `--login` response, as
+//! picked out with `--login-token`, or by the OAuth2 token endpoint.
+//!
+//! The token is sent along with the upgrade request, either in an
+//! `Authorization: Bearer` header or as a query parameter. When the response
+//! says when the token expires, it is reused when reconnecting until shortly
+//! before that, and login is only done again once it is about to expire.
+use rustc_serialize::json::Json;
+
+use cookies::unix_time;
+use json_path::JsonPath;
+
+/// How many seconds before it expires a token is refreshed
+pub const REFRESH_MARGIN: u64 = 30;
+
+/// A token from a login response
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub value: String,
+
+    /// The Unix time the token expires at, if the response said
+    pub expires_at: Option<u64>,
+
+    /// A token to get a new token with once this one expires, if the
+    /// response had one
+    pub refresh_token: Option<String>
+}
+
+impl Token {
+
+    /// Find the token at `path` in a login response. Its lifetime and
+    /// refresh token are read from the `expires_in` and `refresh_token`
+    /// values next to it, as in `{"access_token": "..", "expires_in": 3600}`.
+    pub fn from_response(body: &str, path: &JsonPath) -> Option<Token> {
+        let json = Json::from_str(body).ok()?;
+
+        let value = match path.find(&json) {
+            Some(&Json::String(ref value)) if !value.is_empty() => value.clone(),
+            _ => return None
+        };
+
+        let expires_in = path.sibling("expires_in")
+            .and_then(|path| path.find(&json).and_then(seconds));
+
+        let refresh_token = match path.sibling("refresh_token")
+            .and_then(|path| path.find(&json).cloned()) {
+            Some(Json::String(refresh_token)) => Some(refresh_token),
+            _ => None
+        };
+
+        Some(Token {
+            value: value,
+            expires_at: expires_in.map(|expires_in| unix_time() + expires_in),
+            refresh_token: refresh_token
+        })
+    }
+
+    /// Whether the token expires within `REFRESH_MARGIN` seconds, and
+    /// should be replaced before connecting. Tokens without a known
+    /// lifetime are never reused.
+    pub fn needs_refresh(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => unix_time() + REFRESH_MARGIN >= expires_at,
+            None => true
+        }
+    }
+}
+
+/// A number of seconds, which some servers send as a string
+fn seconds(json: &Json) -> Option<u64> {
+    match *json {
+        Json::U64(seconds) => Some(seconds),
+        Json::I64(seconds) if seconds >= 0 => Some(seconds as u64),
+        Json::F64(seconds) if seconds >= 0.0 => Some(seconds as u64),
+        Json::String(ref seconds) => seconds.trim().parse().ok(),
+        _ => None
+    }
+}
+
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn tokens_are_read_from_responses() {
+    let path = JsonPath::parse(".data.access_token").unwrap();
+
+    let body = r#"{"data": {"access_token": "abc", "expires_in": "3600"}}"#;
+    let token = Token::from_response(body, &path).unwrap();
+    assert_eq!(token.value, "abc");
+    assert_eq!(token.expires_at.map(|at| at - unix_time() > 3500), Some(true));
+    assert!(!token.needs_refresh());
+    assert_eq!(token.refresh_token, None);
+
+    let body = r#"{"data": {"access_token": "abc", "expires_in": 10,
+                            "refresh_token": "def"}}"#;
+    let token = Token::from_response(body, &path).unwrap();
+    assert!(token.needs_refresh());
+    assert_eq!(token.refresh_token, Some(String::from("def")));
+
+    let body = r#"{"data": {"access_token": "abc"}, "expires_in": 3600}"#;
+    let token = Token::from_response(body, &path).unwrap();
+    assert_eq!(token.expires_at, None);
+    assert!(token.needs_refresh());
+
+    assert_eq!(Token::from_response(r#"{"data": {"access_token": 1}}"#, &path), None);
+    assert_eq!(Token::from_response("<html>", &path), None);
+
+    // A token that is the whole response has nothing next to it
+    let token = Token::from_response(r#""abc""#, &JsonPath::parse(".").unwrap());
+    assert_eq!(token.map(|token| token.expires_at), Some(None));
+}
@@ -1,67 +1,196 @@
 use std::io;
 use std::io::{Read, Write, ErrorKind};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender as ChannelSender, Receiver as ChannelReceiver};
 use std::thread;
-use std::process::exit;
-use std::time::{SystemTime, Duration};
+use std::time::{Duration, Instant};
+
+use regex::Regex;
 
 use websocket::{Message, Sender, Receiver};
-use websocket::client::Sender as SenderObj;
-use websocket::client::Receiver as ReceiverObj;
-use websocket::stream::WebSocketStream;
-use websocket::result::WebSocketError;
+use wsta::deflate::Sender as SenderObj;
+use wsta::deflate::Receiver as ReceiverObj;
+use websocket::message::Type;
+use websocket::result::{WebSocketError, WebSocketResult};
+
+use wsta::Error;
+use wsta::options::Options;
 
 use frame_data::FrameData;
+use output::{Output, Direction};
+use program::exit_with;
+
+/// Something the thread owning the `Sender` needs to react to. Events from
+/// every other thread are sent through a single channel, so that the thread
+/// owning the `Sender` can block until any of them happens.
+#[derive(Debug)]
+pub enum Event {
+
+    /// A frame read from stdin, or from a recording, which should be sent
+    Frame(FrameData),
+
+    /// There are no more frames to send
+    InputEnded,
+
+    /// Reading the input failed, so there will be no more frames to send
+    InputFailed(Error),
+
+    /// A client has connected, in listen mode
+    Connected,
+
+    /// Something happened in the reader thread of the connection with the
+    /// given id
+    Reader(u64, ReaderEvent),
+
+    /// SIGINT or SIGTERM was received, and wsta should exit with the given
+    /// code
+    Signal(i32)
+}
+
+/// Something the WebSocket reader thread needs the thread owning the
+/// `Sender` to know about
+#[derive(Debug)]
+pub enum ReaderEvent {
+
+    /// A ping was received, and should be answered with a pong carrying
+    /// the same payload
+    Ping(Vec<u8>),
+
+    /// A pong was received at the given time
+    Pong(Message<'static>, Instant),
+
+    /// A text or binary frame was received, and has been printed. Holds
+    /// whether it matched `--until`.
+    Data(bool),
+
+    /// A close frame was received, with its close code and reason. No more
+    /// events follow.
+    Close(Option<u16>, String),
+
+    /// The connection has been lost
+    Disconnected
+}
+
+/// Sends pings at the interval given by the options, and keeps track of the
+/// pongs received in return
+pub struct Pinger {
+    interval: Option<Duration>,
+    timeout: Option<Duration>,
+    text: bool,
+    message: String,
+
+    /// When the last ping was sent, or when the Pinger was created
+    last_ping: Instant,
+
+    /// When the oldest ping that has not been answered yet was sent
+    unanswered: Option<Instant>
+}
 
 /// Spawn a thread to read stdin. This must be done in a thread because reading
 /// io is a blocking action, and thus the thread reading stdin cannot be the
 /// same thread as the one sending WebSocket messages.
 ///
-/// State is shared with the thread sending WebSocket messages using lockable
-/// shared mutable state - a Mutex.
+/// Every frame read is sent to `events` as an `Event::Frame`, followed by
+/// `Event::InputEnded` once stdin has reached EOF, or `Event::InputFailed`
+/// if stdin could not be read.
 ///
 /// Function has a static lifetime so the thread does not outlive the function
 /// that owns it.
 // TODO Move to ws_writer.rs
-pub fn spawn_stdin_reader<A: 'static>(echo: bool,
-                                      binary_mode: bool,
-                                      frame_size: String) -> Arc<Mutex<Vec<FrameData>>> {
-
-    let arc = Arc::new(Mutex::new(Vec::<FrameData>::new()));
-    let stdin_buffer = arc.clone();
+pub fn spawn_stdin_reader<A: 'static>(binary_mode: bool,
+                                      frame_size: String,
+                                      events: ChannelSender<Event>) {
 
     thread::spawn(move || {
         log!(3, "stdin reader thread spawned");
 
         loop {
 
-            if binary_mode {
-                read_as_binary(&stdin_buffer, frame_size.clone());
+            let result = if binary_mode {
+                read_as_binary(&events, frame_size.clone())
             } else {
-                read_as_utf8(&stdin_buffer, echo);
-            }
+                read_as_utf8(&events)
+            };
 
-            // When looping noninteractively, sleep for a little bit to
-            // ensure we don't eat the processor
-            thread::sleep(Duration::from_millis(50));
+            match result {
+                Ok(true) => {},
+                Ok(false) => {
+                    log!(2, "Reached EOF in stdin");
+                    events.send(Event::InputEnded).unwrap_or(());
+                    break;
+                },
+                Err(err) => {
+                    log!(1, "Error: {:?}", err);
+                    let err = Error::Io(String::from("Could not read from stdin"),
+                                        err);
+                    events.send(Event::InputFailed(err)).unwrap_or(());
+                    break;
+                }
+            }
         }
     });
-
-    arc
 }
 
 /// Read incoming messages in a separate thread and write them to stdout.
 /// Function has a static lifetime and ownership of the Receiver is moved
 /// to the spawned thread
+///
+/// The pings, pongs and close frames that arrive are sent to `events`,
+/// tagged with `id`, as they need to be handled by the thread owning the
+/// `Sender`. So is `ReaderEvent::Disconnected` when the connection is lost,
+/// and it is up to that thread to decide whether to exit or reconnect.
+///
+/// Text and binary frames are reported as `ReaderEvent::Data`, along with
+/// whether their payload matches `until`.
 // TODO Move to ws_reader.rs
-pub fn spawn_websocket_reader<A: 'static>(mut receiver: ReceiverObj<WebSocketStream>) {
+pub fn spawn_websocket_reader<A: 'static>(mut receiver: ReceiverObj,
+                                          output: Output,
+                                          until: Option<Regex>,
+                                          id: u64,
+                                          events: ChannelSender<Event>) {
+
+    // The thread owning the Sender may have stopped listening if it
+    // noticed the disconnect first, which is fine
+    let report = move |event: ReaderEvent| {
+        events.send(Event::Reader(id, event)).unwrap_or(());
+    };
 
     thread::spawn(move || {
         log!(3, "WebSocket reader thread spawned");
 
         for message in receiver.incoming_messages() {
             match message {
-                Ok(msg) => message_to_stdout(msg),
+                Ok(msg) => {
+                    let msg: Message<'static> = msg;
+
+                    match msg.opcode {
+                        Type::Ping => {
+                            let payload = msg.payload.clone().into_owned();
+                            output.incoming(msg);
+                            report(ReaderEvent::Ping(payload));
+                        },
+                        Type::Pong => {
+                            output.record(Direction::Incoming, &msg);
+                            report(ReaderEvent::Pong(msg, Instant::now()));
+                        },
+                        Type::Close => {
+                            let code = msg.cd_status_code;
+                            let reason = String::from_utf8_lossy(&msg.payload)
+                                .into_owned();
+
+                            output.incoming(msg);
+                            report(ReaderEvent::Close(code, reason));
+                            break;
+                        },
+                        _ => {
+                            let matched = until.as_ref().map_or(false, |until| {
+                                until.is_match(&String::from_utf8_lossy(&msg.payload))
+                            });
+
+                            output.incoming(msg);
+                            report(ReaderEvent::Data(matched));
+                        }
+                    }
+                },
                 Err(err) => {
 
                     // Handle the different types of possible errors
@@ -69,94 +198,185 @@ pub fn spawn_websocket_reader<A: 'static>(mut receiver: ReceiverObj<WebSocketStr
                         WebSocketError::NoDataAvailable => {
                             stderr!("\nDisconnected!");
                             log!(1, "Error: {:?}", err);
-                            exit(2);
                         },
                         _ => {
                             log!(1, "Error: {:?}", err);
                             stderr!("Error in WebSocket reader: {}", err);
-                            exit(2);
                         }
                     }
+
+                    report(ReaderEvent::Disconnected);
+                    break;
                 }
             }
         }
     });
 }
 
-/// Reads the `stdin_buffer` and sends the message using the provided
-/// `Sender` if any messages are found. It then flushes the buffer.
-pub fn read_stdin_buffer(sender: &mut SenderObj<WebSocketStream>,
-                         stdin_buffer: Arc<Mutex<Vec<FrameData>>>) {
+/// Wait for the next event, or until `deadline` has passed
+///
+/// # Returns
+/// Returns None if the deadline passed first.
+pub fn next_event(events: &ChannelReceiver<Event>,
+                  deadline: Option<Instant>) -> Option<Event> {
+
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if deadline <= now {
+                return None;
+            }
 
-    // Lock and read string vector from buffer
-    let mut vec = stdin_buffer.lock().unwrap();
+            events.recv_timeout(deadline - now).ok()
+        },
 
-    // Use a draining iterator to read and empty buffer
-    for line in vec.drain(..) {
+        // The thread waiting for events keeps a sender of its own, so
+        // this only fails if something is seriously wrong
+        None => Some(events.recv().expect("Event channel closed"))
+    }
+}
 
-        log!(4, "Read: {:?}", line);
+/// Send a frame read from stdin using the provided `Sender`.
+///
+/// Errors from the `Sender` are returned, as they usually mean that the
+/// connection has been lost.
+pub fn send_frame(sender: &mut SenderObj,
+                  frame: FrameData,
+                  output: &Output) -> WebSocketResult<()> {
 
-        let message = if line.is_utf8() {
-            Message::text(format!("{}", line.utf8.unwrap().trim()))
-        } else {
-            Message::binary(line.binary.unwrap())
-        };
+    log!(4, "Read: {:?}", frame);
 
-        match sender.send_message(&message) {
-            Err(err) => {
-                log!(1, "Error object: {:?}", err);
-                stderr!("An error occured while sending message {:?}: {}",
-                        message, err);
-                exit(1);
-            },
-            _ => {}
-        };
+    let message = frame_to_message(frame);
+    send_message(sender, &message, output)
+}
+
+/// Send a message using the provided `Sender`, and pass it on to `output`
+/// to be echoed and recorded. Errors are printed before they are returned.
+pub fn send_message(sender: &mut SenderObj,
+                    message: &Message,
+                    output: &Output) -> WebSocketResult<()> {
+
+    if let Err(err) = sender.send_message(message) {
+        log!(1, "Error object: {:?}", err);
+        stderr!("An error occured while sending message {:?}: {}",
+                message, err);
+        return Err(err);
     }
+
+    output.outgoing(message);
+
+    Ok(())
 }
 
-/// Check if the provided interval has passed.
-/// If it has passed, send a "ping" frame to the provided sender.
-///
-/// # Returns
-/// Returns last_time if interval has not passed.
-/// If the interval has passed, check_ping_interval returns SystemTime::now()
-pub fn check_ping_interval(ping_interval: &Option<Duration>,
-                           last_time: SystemTime,
-                           sender: &mut SenderObj<WebSocketStream>,
-                           echo: bool,
-                           ping_msg: &String) -> SystemTime {
-
-    if ping_interval.is_some() {
-        let now = SystemTime::now();
-        let time_passed = now.duration_since(last_time).unwrap().as_secs();
-
-        if time_passed >= ping_interval.unwrap().as_secs() {
-            if echo {
-                println!("> {}", ping_msg);
+/// Answer a ping with a pong carrying the same payload
+pub fn send_pong(sender: &mut SenderObj,
+                 payload: Vec<u8>,
+                 output: &Output) -> WebSocketResult<()> {
+    send_message(sender, &Message::pong(payload), output)
+}
+
+/// Convert a frame read from stdin into a text or binary `Message`
+pub fn frame_to_message(frame: FrameData) -> Message<'static> {
+    if frame.is_utf8() {
+        Message::text(format!("{}", frame.utf8.unwrap().trim()))
+    } else {
+        Message::binary(frame.binary.unwrap())
+    }
+}
+
+impl Pinger {
+    pub fn from_options(options: &Options) -> Pinger {
+        Pinger {
+            interval: options.ping_interval.map(Duration::from_secs),
+            timeout: match options.ping_timeout {
+                0 => None,
+                timeout => Some(Duration::from_secs(timeout))
+            },
+            text: options.ping_text,
+            message: options.ping_msg.clone(),
+            last_ping: Instant::now(),
+            unanswered: None
+        }
+    }
+
+    /// Check if the ping interval has passed, and if so, send a ping frame
+    /// to the provided sender. With `--ping-text`, a text frame is sent
+    /// instead, and no pong is expected in return.
+    ///
+    /// An error is returned if the ping could not be sent, or if a ping has
+    /// not been answered within the ping timeout.
+    pub fn check(&mut self,
+                 sender: &mut SenderObj,
+                 output: &Output) -> Result<(), Error> {
+
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return Ok(())
+        };
+
+        if let (Some(sent), Some(timeout)) = (self.unanswered, self.timeout) {
+            if sent.elapsed() >= timeout {
+                stderr!("No pong received within {} seconds, closing connection",
+                        timeout.as_secs());
+                return Err(Error::WebSocket(WebSocketError::IoError(
+                    io::Error::new(ErrorKind::TimedOut, "ping timeout"))));
             }
+        }
 
-            let frame = Message::text(format!("{}", ping_msg));
-            match sender.send_message(&frame) {
-                Err(err) => {
-                    log!(1, "Error object: {:?}", err);
-                    stderr!("An error occured while sending message {:?}: {}",
-                            frame, err);
-                    exit(1);
-                },
-                _ => {}
-            };
+        if self.last_ping.elapsed() < interval {
+            return Ok(());
+        }
+
+        self.last_ping = Instant::now();
+
+        let message = if self.text {
+            Message::text(self.message.clone())
+        } else {
+            if self.unanswered.is_none() {
+                self.unanswered = Some(self.last_ping);
+            }
+
+            Message::ping(self.message.clone().into_bytes())
+        };
 
-            return now
+        send_message(sender, &message, output).map_err(Error::Send)
+    }
+
+    /// The time at which `check` needs to be called next, if ever
+    pub fn deadline(&self) -> Option<Instant> {
+        let next_ping = match self.interval {
+            Some(interval) => self.last_ping + interval,
+            None => return None
+        };
+
+        match (self.unanswered, self.timeout) {
+            (Some(sent), Some(timeout)) if sent + timeout < next_ping => {
+                Some(sent + timeout)
+            },
+            _ => Some(next_ping)
         }
     }
 
-    last_time
+    /// Mark the pings sent so far as answered by a pong received at
+    /// `received`.
+    ///
+    /// # Returns
+    /// Returns the round trip time of the last ping, or None if no ping was
+    /// waiting for an answer.
+    pub fn pong(&mut self, received: Instant) -> Option<Duration> {
+        match self.unanswered.take() {
+            Some(_) if received >= self.last_ping => {
+                Some(received.duration_since(self.last_ping))
+            },
+            _ => None
+        }
+    }
 }
 
 /// Read binary data from stdin in chunks of binary_mode
-/// and write it to stdin_buffer
-fn read_as_binary(stdin_buffer: &Arc<Mutex<Vec<FrameData>>>,
-                  frame_size_str: String) {
+/// and send it to `events`. Returns false at EOF.
+fn read_as_binary(events: &ChannelSender<Event>,
+                  frame_size_str: String) -> io::Result<bool> {
 
     // Parse WSTA_BINARY_FRAME_SIZE environment variable
     // as the size of the binary buffer, or use a global
@@ -164,35 +384,35 @@ fn read_as_binary(stdin_buffer: &Arc<Mutex<Vec<FrameData>>>,
     let frame_size = match frame_size_str.parse() {
         Ok(result) => result,
         Err(error) => {
-            stderr!("Error! WSTA_BINARY_FRAME_SIZE must be a number: {}", error);
             log!(1, "Error: {:?}", error);
-            exit(1);
+            exit_with(Error::InvalidArgument(format!("WSTA_BINARY_FRAME_SIZE \
+                                                      must be a number: {}",
+                                                     error)));
         }
     };
 
     let mut buf: Vec<u8> = vec![0; frame_size];
     let stdin = io::stdin();
 
-    // Read stdin until buffer is full
-    let read_bytes = match stdin.lock().read(buf.as_mut_slice()) {
-        Ok(read) => read,
-        Err(error) => {
-            match error.kind() {
-                ErrorKind::UnexpectedEof => log!(1, "Stdin reader: EOF in stdin"),
-                _ => {
-                    stderr!("Could not read binary frame from stdin: {}", error);
-                    log!(1, "Error: {:?}", error);
-                }
-            }
-
-            // Read no bytes, return 0
-            0
+    // Read stdin until buffer is full, retrying if a signal interrupted
+    // the read
+    let read_bytes = loop {
+        match stdin.lock().read(buf.as_mut_slice()) {
+            Ok(read) => break read,
+            Err(ref error) if error.kind() == ErrorKind::Interrupted => {
+                log!(3, "Reading stdin was interrupted, retrying");
+            },
+            Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => {
+                log!(1, "Stdin reader: EOF in stdin");
+                break 0;
+            },
+            Err(error) => return Err(error)
         }
     };
 
     if read_bytes == 0 {
       log!(3, "No bytes were read");
-      return
+      return Ok(false)
     }
 
     log!(3, "Read {} bytes of binary data", read_bytes);
@@ -212,33 +432,29 @@ fn read_as_binary(stdin_buffer: &Arc<Mutex<Vec<FrameData>>>,
         // Convert to FrameData object
         let frame_data = FrameData::from_binary_buffer(buf);
 
-        // Insert into stdin_buffer
-        stdin_buffer.lock().unwrap().push(frame_data);
+        // Pass on to the thread owning the Sender
+        events.send(Event::Frame(frame_data)).unwrap_or(());
     }
+
+    Ok(true)
 }
 
-/// Read UTF-8 from stdin and write it to stdin_buffer
-fn read_as_utf8(stdin_buffer: &Arc<Mutex<Vec<FrameData>>>,
-                echo: bool) {
+/// Read UTF-8 from stdin and send it to `events`. Returns false at EOF.
+fn read_as_utf8(events: &ChannelSender<Event>) -> io::Result<bool> {
 
     let mut string_buf = String::new();
 
     // Will block until a stdin-line is read
     match io::stdin().read_line(&mut string_buf) {
+        Ok(0) => return Ok(false),
         Ok(_) => {
 
             // Only send non-empty lines to server
             if !string_buf.trim().is_empty() {
 
-                // Print when ehco is active
-                if echo {
-                    println!("> {}", string_buf.trim());
-                }
-
-                // Lock and place read string into buffer
-                log!(3, "Placing message into stdin_buffer: {}", string_buf);
+                log!(3, "Read message from stdin: {}", string_buf);
                 let frame_data = FrameData::from_utf8(string_buf);
-                stdin_buffer.lock().unwrap().push(frame_data);
+                events.send(Event::Frame(frame_data)).unwrap_or(());
             }
         },
         Err(error) => {
@@ -249,31 +465,39 @@ fn read_as_utf8(stdin_buffer: &Arc<Mutex<Vec<FrameData>>>,
                     stderr!("InvalidData. Is input not UTF-8? Use UTF-8 or try binary mode (-b)");
                     log!(1, "error: {:?}", error);
                 },
-                _ => {
-                    stderr!("error: {}", error);
-                    log!(1, "Error: {:?}", error);
-                }
+
+                // Interrupted by a signal, try again
+                ErrorKind::Interrupted => log!(3, "Reading stdin was interrupted"),
+                _ => return Err(error)
             }
         }
     }
+
+    Ok(true)
 }
 
-fn message_to_stdout(message: Message) {
+/// Print the payload of a message to stdout, preceded by `prefix`. Payloads
+/// that are not valid UTF-8 are written as raw bytes.
+pub fn message_to_stdout(message: Message, prefix: &str) {
     let owned = message.payload.into_owned();
 
     match String::from_utf8(owned.clone()) {
-        Ok(result) => println!("{}", result),
+        Ok(result) => println!("{}{}", prefix, result),
         Err(error) => {
 
             // Failed to parse as UTF-8, assume it is binary
             log!(2, "Error: {}. Falling back to binary", error);
             log!(4, "Error: {:?}", error);
 
-            match io::stdout().write(owned.as_ref()) {
+            let mut stdout = io::stdout();
+            let result = stdout.write_all(prefix.as_bytes())
+                .and_then(|_| stdout.write_all(owned.as_ref()));
+
+            match result {
                 Err(error) => {
-                    stderr!("Failed to write message to stdout: {}", error);
                     log!(2, "Error: {:?}", error);
-                    exit(1);
+                    exit_with(Error::Io(String::from("Failed to write message \
+                                                      to stdout"), error));
                 },
                 _ => log!(3, "Printing binary frame")
             }
@@ -281,3 +505,24 @@ fn message_to_stdout(message: Message) {
     }
 }
 
+///////////////////////// TESTS ///////////////////////////
+
+#[test]
+fn pongs_answer_the_last_ping() {
+    let sent = Instant::now();
+    let mut pinger = Pinger {
+        interval: Some(Duration::from_secs(1)),
+        timeout: None,
+        text: false,
+        message: String::from("ping"),
+        last_ping: sent,
+        unanswered: Some(sent)
+    };
+
+    let received = sent + Duration::from_millis(20);
+    assert_eq!(pinger.pong(received), Some(Duration::from_millis(20)));
+
+    // Nothing is waiting for an answer anymore
+    assert_eq!(pinger.unanswered, None);
+    assert_eq!(pinger.pong(received), None);
+}